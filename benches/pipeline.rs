@@ -0,0 +1,60 @@
+//! Throughput of the whole alignment pipeline on a synthetic dataset, in reads per second
+//!
+//! fastalign is a binary crate, so each iteration runs the built binary with --benchmark-mode,
+//! which aligns everything but skips writing the aligned rows.
+
+use std::path::Path;
+use std::process::Command;
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+
+#[path = "../tests/support/mod.rs"]
+mod support;
+
+const REFERENCE_LEN: usize = 30_000;
+const READ_COUNT: usize = 2_000;
+const READ_LEN: usize = 1_000;
+
+const THREADS: [usize; 3] = [1, 2, 4];
+/// Channel capacities to compare, `None` leaves the channels unbounded
+const CHANNEL_CAPACITIES: [Option<usize>; 3] = [None, Some(16), Some(256)];
+
+fn run_fastalign(data: &support::SyntheticData, output: &Path, threads: usize, channel_capacity: Option<usize>) {
+    let mut command = Command::new(env!("CARGO_BIN_EXE_fastalign"));
+    command
+        .arg("run")
+        .arg("-i").arg(&data.reads)
+        .arg("-r").arg(&data.reference)
+        .arg("-o").arg(output)
+        .arg("--threads").arg(threads.to_string())
+        .arg("--benchmark-mode")
+        .arg("--quiet");
+    if let Some(capacity) = channel_capacity {
+        command.arg("--channel-capacity").arg(capacity.to_string());
+    }
+    let status = command.status().expect("failed to run fastalign");
+    assert!(status.success(), "fastalign exited with {}", status);
+}
+
+fn pipeline(c: &mut Criterion) {
+    let dir = tempfile::tempdir().expect("failed to create benchmark directory");
+    let data = support::write_dataset(dir.path(), REFERENCE_LEN, READ_COUNT, READ_LEN, 42)
+        .expect("failed to write synthetic dataset");
+    let output = dir.path().join("aln.fa");
+
+    let mut group = c.benchmark_group("pipeline");
+    group.sample_size(10);
+    group.throughput(Throughput::Elements(data.read_count as u64));
+    for threads in THREADS {
+        for channel_capacity in CHANNEL_CAPACITIES {
+            let capacity = channel_capacity.map_or("unbounded".to_string(), |capacity| capacity.to_string());
+            let id = BenchmarkId::new(format!("threads={}", threads), format!("capacity={}", capacity));
+            group.bench_with_input(id, &(threads, channel_capacity), |b, &(threads, channel_capacity)| {
+                b.iter(|| run_fastalign(&data, &output, threads, channel_capacity));
+            });
+        }
+    }
+    group.finish();
+}
+
+criterion_group!(benches, pipeline);
+criterion_main!(benches);
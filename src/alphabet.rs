@@ -0,0 +1,69 @@
+/// Nucleotide alphabet of a sequence
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Alphabet {
+    Dna,
+    Rna,
+}
+
+/// Detect the alphabet from whether the sequence contains T or U
+/// Returns None when it has neither or both, as the alphabet can't be told apart
+pub fn detect(sequence: &[u8]) -> Option<Alphabet> {
+    detect_all(std::iter::once(sequence))
+}
+
+/// Detect the shared alphabet of several sequences, such as all contigs of a reference
+pub fn detect_all<'a>(sequences: impl Iterator<Item = &'a [u8]>) -> Option<Alphabet> {
+    let (mut has_t, mut has_u) = (false, false);
+    for base in sequences.flatten() {
+        match base.to_ascii_uppercase() {
+            b'T' => has_t = true,
+            b'U' => has_u = true,
+            _ => {}
+        }
+    }
+    match (has_t, has_u) {
+        (true, false) => Some(Alphabet::Dna),
+        (false, true) => Some(Alphabet::Rna),
+        _ => None,
+    }
+}
+
+/// Convert T to U or U to T in place, preserving case
+pub fn convert(sequence: &mut [u8], to: Alphabet) {
+    let (from, into) = match to {
+        Alphabet::Dna => (b'U', b'T'),
+        Alphabet::Rna => (b'T', b'U'),
+    };
+    for base in sequence.iter_mut() {
+        if *base == from {
+            *base = into;
+        } else if *base == from.to_ascii_lowercase() {
+            *base = into.to_ascii_lowercase();
+        }
+    }
+}
+
+/// Complement of a single base, preserving case and IUPAC ambiguity codes
+fn complement(base: u8) -> u8 {
+    let complement = match base.to_ascii_uppercase() {
+        b'A' => b'T',
+        b'T' | b'U' => b'A',
+        b'C' => b'G',
+        b'G' => b'C',
+        b'R' => b'Y',
+        b'Y' => b'R',
+        b'K' => b'M',
+        b'M' => b'K',
+        b'B' => b'V',
+        b'V' => b'B',
+        b'D' => b'H',
+        b'H' => b'D',
+        other => other,
+    };
+    if base.is_ascii_lowercase() { complement.to_ascii_lowercase() } else { complement }
+}
+
+/// Reverse complement of a sequence, gaps are kept as they are
+pub fn reverse_complement(sequence: &[u8]) -> Vec<u8> {
+    sequence.iter().rev().map(|&base| complement(base)).collect()
+}
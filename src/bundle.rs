@@ -0,0 +1,17 @@
+use std::fs::File;
+use std::path::Path;
+use anyhow::{Result, Context};
+use flate2::Compression;
+use flate2::write::GzEncoder;
+
+/// Bundle the output files into a gzipped tar archive, each entry named after its file
+pub fn write_bundle(path: &str, files: &[&str]) -> Result<()> {
+    let file = File::create(path).context(format!("Failed to create bundle: {}", path))?;
+    let mut archive = tar::Builder::new(GzEncoder::new(file, Compression::default()));
+    for file in files {
+        let name = Path::new(file).file_name().context(format!("Invalid output path: {}", file))?;
+        archive.append_path_with_name(file, name).context(format!("Failed to add {} to bundle", file))?;
+    }
+    archive.into_inner().context("Failed to write bundle")?.finish().context("Failed to write bundle")?;
+    Ok(())
+}
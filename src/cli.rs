@@ -1,21 +1,76 @@
-use clap::Parser;
+use std::ffi::OsString;
+use clap::{ArgAction, Args, Parser, Subcommand, ValueEnum};
 
 #[derive(Parser, Debug)]
 #[command(
     author, version, about = "Quick multiple sequnce alignment using minimap2", long_about = None
 )]
 pub struct Cli {
-    /// Input (unaligned) FASTA file.
+    #[command(subcommand)]
+    pub command: Command,
+
+    /// Only print errors.
+    #[arg(short = 'q', long = "quiet", global = true, conflicts_with = "verbose")]
+    pub quiet: bool,
+
+    /// Print more about what the run is doing, -v for progress and -vv for per-read detail.
+    #[arg(short = 'v', long = "verbose", global = true, action = ArgAction::Count)]
+    pub verbose: u8,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum Command {
+    /// Align an input FASTA against the reference. Used when no subcommand is given.
+    Run(RunArgs),
+    /// Align a single sequence and print its CIGAR, coordinates and aligned row.
+    Query(QueryArgs),
+}
+
+/// Parse the command line, running the `run` subcommand when none is given
+pub fn parse() -> Cli {
+    let mut args: Vec<OsString> = std::env::args_os().collect();
+    // Global flags can come before the subcommand, e.g. `fastalign -v query ...`
+    let has_subcommand = args.iter().skip(1)
+        .map(|arg| arg.to_str())
+        .find(|arg| !arg.is_some_and(is_global_flag))
+        .flatten()
+        .is_some_and(|arg| matches!(arg, "run" | "query" | "help" | "-h" | "--help" | "-V" | "--version"));
+    if !has_subcommand {
+        args.insert(1, OsString::from("run"));
+    }
+    Cli::parse_from(args)
+}
+
+/// Whether an argument is one of the global flags, -q, -v, -vv and so on
+fn is_global_flag(arg: &str) -> bool {
+    match arg {
+        "-q" | "--quiet" | "--verbose" => true,
+        _ => arg.strip_prefix('-').is_some_and(|flags| !flags.is_empty() && flags.bytes().all(|flag| flag == b'v')),
+    }
+}
+
+#[derive(Args, Debug)]
+pub struct RunArgs {
+    /// Input (unaligned) FASTA or FASTQ file, or - for stdin.
     #[arg(
         short = 'i', long = "input", value_name = "Unaligned FASTA", required_unless_present = "help", value_parser(check_input_exists)
     )]
     pub input: String,
 
-    /// Input reference FASTA file.
+    /// Rename reads in the output using a TSV of old and new names, reads not listed keep their name.
+    #[arg(long = "rename-map", value_name = "Rename TSV", value_parser(check_input_exists))]
+    pub rename_map: Option<String>,
+
+    /// Force the input format rather than detecting it from the first record.
+    #[arg(long = "input-format", value_name = "Format")]
+    pub input_format: Option<InputFormat>,
+
+    /// Input reference FASTA file(s).
+    /// When several are given each read is aligned to whichever reference it matches best.
     #[arg(
-        short = 'r', long = "reference", value_name = "Reference FASTA", required_unless_present = "help", value_parser(check_input_exists)
+        short = 'r', long = "reference", value_name = "Reference FASTA", required_unless_present = "help", num_args = 1.., value_parser(check_input_exists)
     )]
-    pub reference: String,
+    pub reference: Vec<String>,
 
     /// Output alignment file.
     #[arg(
@@ -23,11 +78,483 @@ pub struct Cli {
     )]
     pub output: String,
 
+    /// Output format.
+    /// fasta: aligned FASTA, matrix: TSV with one line per reference column and one field per read,
+    /// stockholm: Stockholm alignment with reference and consensus annotation,
+    /// bed: alignment coordinates only (0-based), skipping CIGAR computation,
+    /// paf: minimap2 pairwise mapping format, also skipping CIGAR computation,
+    /// html: colour-coded static HTML table with mismatches against the reference highlighted,
+    /// pretty: wrapped blocks with the reference on top and a conservation line marking conserved columns with *
+    #[arg(long = "out-format", value_name = "Format", default_value = "fasta")]
+    pub out_format: OutFormat,
+
+    #[command(flatten)]
+    pub aligner: AlignerArgs,
+
     /// Number of threads to use.
     /// Default: 1
     #[arg(short = 't', long = "threads", value_name = "Threads", default_value = "1")]
     pub threads: usize,
 
+    /// Number of threads compressing BGZF output, separate from the alignment threads.
+    #[arg(long = "threads-io", value_name = "Threads", default_value = "1", value_parser = parse_non_zero)]
+    pub threads_io: usize,
+
+    /// Maximum number of record batches or aligned reads queued between pipeline stages, unbounded if not set.
+    #[arg(long = "channel-capacity", value_name = "Capacity", value_parser = parse_non_zero)]
+    pub channel_capacity: Option<usize>,
+
+    /// Left-align deletions in low-complexity regions so equivalent gaps are placed consistently.
+    #[arg(long = "normalize-indels")]
+    pub normalize_indels: bool,
+
+    /// Mask low confidence aligned bases with N.
+    /// Bases within --mask-flank of a clipped read end are masked, and whole rows are masked when MAPQ is below --mask-min-mapq.
+    #[arg(long = "mask-lowqual", visible_alias = "ambiguous-to-n")]
+    pub mask_lowqual: bool,
+
+    /// Number of aligned bases next to a clipped read end to mask with --mask-lowqual.
+    /// Default: 10
+    #[arg(long = "mask-flank", value_name = "Bases", default_value = "10")]
+    pub mask_flank: usize,
+
+    /// MAPQ below which every aligned base is masked with --mask-lowqual.
+    /// Default: 20
+    #[arg(long = "mask-min-mapq", value_name = "MAPQ", default_value = "20")]
+    pub mask_min_mapq: u32,
+
+    /// Strip a leading UTF-8 BOM and convert CRLF line endings to LF in the input FASTA.
+    /// Default: true
+    #[arg(long = "sanitize-input", value_name = "true|false", default_value_t = true, action = clap::ArgAction::Set)]
+    pub sanitize_input: bool,
+
+    /// Write the majority-rule consensus of the alignment to this FASTA file. Needs untranslated rows.
+    #[arg(long = "consensus", value_name = "Consensus FASTA", conflicts_with = "translate")]
+    pub consensus: Option<String>,
+
+    /// Write one consensus per group, keyed by the part of each read name before this delimiter.
+    #[arg(long = "group-by", value_name = "Delimiter", requires = "consensus")]
+    pub group_by: Option<String>,
+
+    /// Group reads by a field of their name instead, given as DELIMITER:FIELD with a 1-based field (e.g. ".:1").
+    #[arg(long = "name-field", value_name = "Spec", requires = "consensus", conflicts_with = "group_by", value_parser = parse_name_field)]
+    pub name_field: Option<NameField>,
+
+    /// Fill gap columns of the consensus with the base of the contig the group aligned to.
+    #[arg(long = "consensus-fill-ref", requires = "consensus")]
+    pub consensus_fill_ref: bool,
+
+    /// Report the mean per-column identity of the reads to their consensus once the run finishes. Needs a single reference contig.
+    #[arg(long = "msa-identity", conflicts_with_all = ["translate", "no_pad"])]
+    pub msa_identity: bool,
+
+    /// How to call consensus columns where the top base count is tied.
+    /// n: N, ref: the reference base, iupac: the IUPAC code for the tied bases
+    #[arg(long = "consensus-tie", value_name = "Tie", default_value = "n", requires = "consensus")]
+    pub consensus_tie: ConsensusTie,
+
+    /// Write the consensus base and fraction of reads supporting it at each column to this TSV.
+    #[arg(long = "consensus-freq", value_name = "Frequency TSV", requires = "consensus")]
+    pub consensus_freq: Option<String>,
+
+    /// Print time spent reading, aligning and writing once the run finishes.
+    #[arg(long = "profile")]
+    pub profile: bool,
+
+    /// Run the full pipeline but discard the aligned output, then report throughput in reads/s and MB/s.
+    /// The output file is not created, sidecar files are still written.
+    #[arg(long = "benchmark-mode", conflicts_with_all = ["append", "chunk_output", "bundle"])]
+    pub benchmark_mode: bool,
+
+    /// Output the aligned rows translated to amino acids.
+    #[arg(long = "translate")]
+    pub translate: bool,
+
+    /// Reading frame (reference offset 0, 1 or 2) used with --translate.
+    /// Default: 0
+    #[arg(long = "frame", value_name = "Frame", default_value = "0", value_parser = clap::value_parser!(u8).range(0..3), requires = "translate")]
+    pub frame: u8,
+
+    /// Only align the reads with these names, given as a comma separated list or a file with one name per line.
+    #[arg(long = "names", value_name = "Names")]
+    pub names: Option<String>,
+
+    /// Treat lowercase read bases as masked, aligning them as N.
+    /// minimap2 scores N as ambiguous, and with this tool's settings it neither adds to nor costs the alignment score.
+    #[arg(long = "respect-read-mask")]
+    pub respect_read_mask: bool,
+
+    /// Trim these primer sequences, or their reverse complements, from the ends of reads before alignment.
+    #[arg(long = "primers", value_name = "Primer FASTA", value_parser(check_input_exists))]
+    pub primers: Option<String>,
+
+    /// Mismatches allowed when matching a primer to the end of a read.
+    #[arg(long = "primer-mismatches", value_name = "Mismatches", default_value = "1", requires = "primers")]
+    pub primer_mismatches: usize,
+
+    /// Drop reads shorter than this before alignment.
+    /// Default: 0
+    #[arg(long = "min-read-len", value_name = "Length", default_value = "0")]
+    pub min_read_len: usize,
+
+    /// Drop reads longer than this before alignment, guarding against concatemers.
+    #[arg(long = "max-read-len", value_name = "Length", value_parser = parse_non_zero)]
+    pub max_read_len: Option<usize>,
+
+    /// Error on reads longer than --max-read-len instead of dropping them.
+    #[arg(long = "fail-on-long-reads", requires = "max_read_len")]
+    pub fail_on_long_reads: bool,
+
+    /// Drop reads whose alignment spans less than this fraction of their reference contig.
+    #[arg(long = "min-ref-coverage", value_name = "Fraction", value_parser = parse_fraction)]
+    pub min_ref_coverage: Option<f64>,
+
+    /// Drop reads that map without consuming any reference bases, whose rows would be all padding.
+    #[arg(long = "drop-unanchored")]
+    pub drop_unanchored: bool,
+
+    /// Trim each row to its alignment span rather than padding it to the reference, the start is added to the header.
+    #[arg(long = "no-pad", conflicts_with_all = ["pad_equal", "translate", "consensus", "collapse_identical"])]
+    pub no_pad: bool,
+
+    /// Shift every row by this many columns so alignments against offset references line up.
+    /// Positive offsets add leading gaps, negative offsets drop that many leading columns. Coordinates are unchanged.
+    #[arg(long = "reference-offset", value_name = "Columns", default_value = "0", allow_negative_numbers = true, conflicts_with = "no_pad")]
+    pub reference_offset: i64,
+
+    /// Pad rows with . where the read doesn't cover the reference, keeping - for deletions within the alignment.
+    #[arg(long = "distinguish-coverage", conflicts_with = "translate")]
+    pub distinguish_coverage: bool,
+
+    /// Pad every row to the length of the longest reference contig so the output is rectangular.
+    /// Columns only line up between contigs if they are treated as one shared coordinate frame.
+    #[arg(long = "pad-equal")]
+    pub pad_equal: bool,
+
+    /// Flush the output file every N records so downstream tools can read it live.
+    /// Default: only flush at the end
+    #[arg(long = "flush-interval", value_name = "Records", value_parser = clap::value_parser!(u64).range(1..))]
+    pub flush_interval: Option<u64>,
+
+    /// Flush the per-read sidecar files through to disk every N records, so a failed run keeps what was written.
+    #[arg(long = "checkpoint-every", value_name = "Records", value_parser = clap::value_parser!(u64).range(1..))]
+    pub checkpoint_every: Option<u64>,
+
+    /// Also bundle the output and every sidecar file into this .tar.gz once the run finishes.
+    #[arg(long = "bundle", value_name = "Archive", conflicts_with = "chunk_output")]
+    pub bundle: Option<String>,
+
+    /// Split FASTA output into files of at most N records, named like aln.part001.fa.
+    #[arg(long = "chunk-output", value_name = "Records", value_parser = clap::value_parser!(u64).range(1..))]
+    pub chunk_output: Option<u64>,
+
+    /// Write identical aligned rows once, with the number of reads in a size=N header field.
+    #[arg(long = "collapse-identical", conflicts_with = "chunk_output")]
+    pub collapse_identical: bool,
+
+    /// Also write the reverse complement of each aligned row, named with an _rc suffix. FASTA output only.
+    #[arg(long = "emit-revcomp", conflicts_with_all = ["translate", "restore_u"])]
+    pub emit_revcomp: bool,
+
+    /// Write rows of reads that aligned to the reverse strand to this FASTA instead of the main output.
+    /// Rows are already in reference orientation, so both files line up column for column.
+    #[arg(long = "split-by-strand", value_name = "Reverse FASTA", conflicts_with_all = ["chunk_output", "collapse_identical", "benchmark_mode"])]
+    pub split_by_strand: Option<String>,
+
+    /// Write BGZF compressed output, suitable for indexing. Implied by a .bgz output extension.
+    #[arg(long = "bgzf")]
+    pub bgzf: bool,
+
+    /// Fill deletions with the reference bases instead of gaps.
+    #[arg(long = "fill-deletions-with-ref")]
+    pub fill_deletions_with_ref: bool,
+
+    /// Compare read and reference bases to split M into matches and mismatches for the metrics.
+    #[arg(long = "resolve-m")]
+    pub resolve_m: bool,
+
+    /// Fill gaps of at most this many bases inside each alignment with the reference bases, longer deletions are kept.
+    #[arg(long = "fill-short-gaps", value_name = "Length", value_parser = parse_non_zero)]
+    pub fill_short_gaps: Option<usize>,
+
+    /// Write per-read alignment metrics to this TSV.
+    #[arg(long = "tsv", value_name = "Metrics TSV")]
+    pub tsv: Option<String>,
+
+    /// Also write the mapped read coordinates to this BED file, alongside the main output.
+    #[arg(long = "bed", value_name = "BED")]
+    pub bed: Option<String>,
+
+    /// Also write each mapped read's alignment span as a GFF3 match feature, with identity, mapq and strand.
+    #[arg(long = "gff", value_name = "GFF3")]
+    pub gff: Option<String>,
+
+    /// Write every segment of reads that align in several pieces, found from supplementary alignments, to this TSV.
+    #[arg(long = "detect-chimera", value_name = "Chimera TSV")]
+    pub detect_chimera: Option<String>,
+
+    /// Write each segment of a chimeric read as its own row, named like read_seg1, read_seg2, ...
+    #[arg(long = "split-chimera")]
+    pub split_chimera: bool,
+
+    /// Write the 5' and 3' ends of each read left unaligned to this TSV.
+    #[arg(long = "clip-report", value_name = "Clip TSV")]
+    pub clip_report: Option<String>,
+
+    /// Write the per-read alignment metrics to this file as JSON lines, one object per read.
+    #[arg(long = "stats-json", value_name = "Metrics JSON")]
+    pub stats_json: Option<String>,
+
+    /// Write the identity between every pair of rows, over the columns both cover, to this TSV.
+    /// Every pair of reads is compared, so this gets slow for large inputs.
+    #[arg(long = "pairwise-identity", value_name = "Identity TSV", conflicts_with = "no_pad")]
+    pub pairwise_identity: Option<String>,
+
+    /// Write the count of each base (A, C, G, T, N and gap) across all reads at every reference position to this TSV.
+    #[arg(long = "pileup", value_name = "Pileup TSV", conflicts_with_all = ["translate", "no_pad"])]
+    pub pileup: Option<String>,
+
+    /// Write every insertion dropped from the aligned rows to this TSV.
+    #[arg(long = "insertions-out", value_name = "Insertions TSV")]
+    pub insertions_out: Option<String>,
+
+    /// Coordinate base (0 or 1) for reference positions in TSV outputs.
+    /// Formats with a fixed convention always use their own.
+    /// Default: 1
+    #[arg(long = "coordinate-base", value_name = "Base", default_value = "1", value_parser = clap::value_parser!(u8).range(0..2))]
+    pub coordinate_base: u8,
+
+    /// Number of reads sent to alignment workers at a time, larger batches help with many short reads.
+    /// Default: 1
+    #[arg(long = "batch-size", value_name = "Reads", default_value = "1", value_parser = parse_non_zero)]
+    pub batch_size: usize,
+
+    /// Convert reads between RNA (U) and DNA (T) to match the reference instead of erroring on a mismatch.
+    #[arg(long = "convert-alphabet")]
+    pub convert_alphabet: bool,
+
+    /// Read the input as RNA, converting U to T before alignment.
+    #[arg(long = "rna")]
+    pub rna: bool,
+
+    /// Write U instead of T in the aligned rows, restoring the RNA alphabet of --rna input.
+    #[arg(long = "restore-u", requires = "rna", conflicts_with = "translate")]
+    pub restore_u: bool,
+
+    /// Only index these reference contigs, given as a comma separated list or a file with one name per line.
+    #[arg(long = "ref-contigs", value_name = "Contigs")]
+    pub ref_contigs: Option<String>,
+
+    /// Only align against this region of the reference, given as NAME:START-END with 1-based inclusive coordinates.
+    /// The region is read with a seek when the reference has a .fai index. Rows span the region only,
+    /// while headers and coordinate sidecars (BED, PAF, GFF, TSV, insertions, pileup) keep positions on the whole contig.
+    #[arg(long = "region", value_name = "Region", conflicts_with_all = ["ref_contigs", "split_on_n"])]
+    pub region: Option<String>,
+
+    /// Append to the output file instead of overwriting it. FASTA, BED and PAF output only,
+    /// since the other formats can't take a second alignment after the first.
+    #[arg(long = "append")]
+    pub append: bool,
+
+    /// Split the reference on runs of at least this many Ns so reads can't align across scaffold gaps.
+    /// Rows are named after the pieces (`<name>_1`, `<name>_2`, ...), while headers and coordinate sidecars keep positions on the whole scaffold.
+    #[arg(long = "split-on-n", value_name = "Min run", value_parser = parse_non_zero)]
+    pub split_on_n: Option<usize>,
+
+    /// Error before indexing if the total reference length is above this.
+    #[arg(long = "max-ref-len", value_name = "Length")]
+    pub max_ref_len: Option<usize>,
+
+    /// Align the longest reads first so they don't hold up the end of the run. Reads the whole input into memory.
+    #[arg(long = "sort-by-length")]
+    pub sort_by_length: bool,
+
+    /// Spill the rows buffered for matrix and Stockholm output to a temporary file here instead of holding them in memory,
+    /// or only once they pass --max-memory when that is set.
+    #[arg(long = "spill-dir", value_name = "Directory")]
+    pub spill_dir: Option<String>,
+
+    /// Keep buffered rows in memory until they take this many bytes, then spill them to disk
+    /// under --spill-dir (or the temporary directory) and stream the rest there.
+    #[arg(long = "max-memory", value_name = "Bytes", value_parser = parse_non_zero)]
+    pub max_memory: Option<usize>,
+
+    /// Only write the N mapped reads with the highest identity. Holds every mapped read in memory until the end.
+    #[arg(long = "top-n", value_name = "Reads", value_parser = parse_non_zero)]
+    pub top_n: Option<usize>,
+
+    /// Write records in the same order as the input.
+    #[arg(long = "keep-order")]
+    pub keep_order: bool,
+
+    /// Write reads that did not map as all-gap rows, in their place among the mapped reads.
+    #[arg(long = "emit-unmapped-rows")]
+    pub emit_unmapped_rows: bool,
+
+    /// Write reads that did not map as all-gap rows, after all of the mapped reads.
+    #[arg(long = "unmapped-last")]
+    pub unmapped_last: bool,
+
+    /// Error instead of warning when minimap2 reports more than one primary alignment for a read, or only secondary ones.
+    #[arg(long = "strict-primary")]
+    pub strict_primary: bool,
+
+    /// What to do when an alignment comes back without a CIGAR, which would otherwise give an all-gap row.
+    /// error: stop the run, unmapped: treat the read as unmapped with a warning
+    #[arg(long = "empty-cigar", value_name = "Policy", default_value = "error")]
+    pub empty_cigar: EmptyCigar,
+
+    /// Error instead of warning when the input is also one of the references.
+    #[arg(long = "no-self-align")]
+    pub no_self_align: bool,
+
+    /// Check each row and its dropped insertions give back the aligned part of the read, erroring if they don't.
+    #[arg(long = "verify-roundtrip", conflicts_with = "fill_deletions_with_ref")]
+    pub verify_roundtrip: bool,
+
+    /// Check that every output row is the expected reference length, erroring on the first that isn't.
+    #[arg(long = "validate-output")]
+    pub validate_output: bool,
+
+    /// Write every sequence exactly as it is handed to minimap2 to this FASTA, after trimming and conversion.
+    #[arg(long = "dump-queries", value_name = "Query FASTA")]
+    pub dump_queries: Option<String>,
+
+    /// Print the resolved minimap2 options to stderr before aligning.
+    #[arg(long = "explain")]
+    pub explain: bool,
+
+    /// Add the read's differences from the reference to its header, e.g. variants=100A>G,150delT,200insAC.
+    #[arg(long = "variant-string")]
+    pub variant_string: bool,
+
+    /// Add the target, start, strand, MAPQ and percent identity to each output record's header.
+    #[arg(long = "annotate-headers")]
+    pub annotate_headers: bool,
+
+}
+
+impl RunArgs {
+    /// How read names are split into groups, --group-by being the first field before its delimiter
+    pub fn group_field(&self) -> Option<NameField> {
+        match &self.group_by {
+            Some(delimiter) => Some(NameField { delimiter: delimiter.clone(), field: 1 }),
+            None => self.name_field.clone(),
+        }
+    }
+
+    /// Whether any output needs the CIGAR, coordinate-only runs can skip computing it
+    pub fn needs_cigar(&self) -> bool {
+        !self.out_format.is_coordinate_only() || self.tsv.is_some() || self.stats_json.is_some()
+    }
+
+    /// Every file the run writes, the main output followed by any sidecars
+    pub fn output_files(&self) -> Vec<&str> {
+        let sidecars = [
+            &self.split_by_strand,
+            &self.tsv,
+            &self.stats_json,
+            &self.bed,
+            &self.gff,
+            &self.clip_report,
+            &self.insertions_out,
+            &self.pairwise_identity,
+            &self.consensus,
+            &self.consensus_freq,
+            &self.pileup,
+            &self.dump_queries,
+            &self.detect_chimera,
+        ];
+        std::iter::once(self.output.as_str())
+            .chain(sidecars.into_iter().flatten().map(String::as_str))
+            .collect()
+    }
+
+    /// Whether reads that did not map get an all-gap placeholder row
+    pub fn emits_unmapped(&self) -> bool {
+        self.emit_unmapped_rows || self.unmapped_last
+    }
+}
+
+#[derive(Args, Debug)]
+pub struct QueryArgs {
+    /// Input reference FASTA file.
+    #[arg(short = 'r', long = "reference", value_name = "Reference FASTA", value_parser(check_input_exists))]
+    pub reference: String,
+
+    /// Sequence to align.
+    #[arg(short = 's', long = "seq", value_name = "Sequence")]
+    pub seq: String,
+
+    #[command(flatten)]
+    pub aligner: AlignerArgs,
+}
+
+/// minimap2 options shared by every subcommand
+#[derive(Args, Debug)]
+pub struct AlignerArgs {
+    /// Index homopolymer-compressed minimizers, useful for noisy long reads (minimap2 -H).
+    /// Only seeding is compressed, coordinates and CIGARs stay in uncompressed reference space so rows are unaffected.
+    #[arg(long = "hpc")]
+    pub hpc: bool,
+
+    /// Lower bound on the minimizer occurrence cutoff, raising it keeps more repetitive seeds (minimap2 -U floor).
+    #[arg(long = "min-occ-floor", value_name = "Count")]
+    pub min_occ_floor: Option<i32>,
+
+    /// Drop minimizers occurring more than this many times, lowering it reduces spurious seeding in repeats.
+    #[arg(long = "max-occ", value_name = "Count")]
+    pub max_occ: Option<i32>,
+
+    /// Gap open penalty for long gaps, the second piece of the affine gap cost (minimap2 -O second value).
+    #[arg(long = "gap-open2", value_name = "Penalty")]
+    pub gap_open2: Option<i32>,
+
+    /// Gap extension penalty for long gaps, lowering it favours large indels (minimap2 -E second value).
+    #[arg(long = "gap-extend2", value_name = "Penalty")]
+    pub gap_extend2: Option<i32>,
+
+    /// Extra minimap2 options as comma separated key=value pairs named after the mm_idxopt_t and mm_mapopt_t fields,
+    /// e.g. "k=19,w=10,bw=1000". Applied after every other option, unknown keys are an error.
+    #[arg(long = "mm2-opts", value_name = "Options")]
+    pub mm2_opts: Option<String>,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq)]
+pub enum InputFormat {
+    Fasta,
+    Fastq,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq)]
+pub enum ConsensusTie {
+    N,
+    Ref,
+    Iupac,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq)]
+pub enum EmptyCigar {
+    Error,
+    Unmapped,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq)]
+pub enum OutFormat {
+    Fasta,
+    Matrix,
+    Stockholm,
+    Bed,
+    Paf,
+    Html,
+    Pretty,
+}
+
+impl OutFormat {
+    /// Formats that only need alignment coordinates, not the aligned rows
+    pub fn is_coordinate_only(self) -> bool {
+        matches!(self, OutFormat::Bed | OutFormat::Paf)
+    }
 }
 
 fn check_input_exists(s: &str) -> Result<String, String> {
@@ -39,4 +566,44 @@ fn check_input_exists(s: &str) -> Result<String, String> {
     } else {
         Err(format!("File does not exist: {}", s))
     }
-}
\ No newline at end of file
+}
+
+/// A field of a structured read name, like the sample in `sampleA.read123`
+#[derive(Clone, Debug)]
+pub struct NameField {
+    pub delimiter: String,
+    /// 1-based index of the field
+    pub field: usize,
+}
+
+impl NameField {
+    /// The field from a read name, or the whole name if it has too few fields
+    pub fn extract(&self, name: &str) -> String {
+        name.split(self.delimiter.as_str()).nth(self.field - 1).unwrap_or(name).to_string()
+    }
+}
+
+fn parse_name_field(s: &str) -> Result<NameField, String> {
+    let (delimiter, field) = s.rsplit_once(':').ok_or("Expected DELIMITER:FIELD")?;
+    if delimiter.is_empty() {
+        return Err("Delimiter must not be empty".to_string());
+    }
+    let field = parse_non_zero(field)?;
+    Ok(NameField { delimiter: delimiter.to_string(), field })
+}
+
+fn parse_fraction(s: &str) -> Result<f64, String> {
+    match s.parse::<f64>() {
+        Ok(f) if (0.0..=1.0).contains(&f) => Ok(f),
+        Ok(_) => Err("Value must be between 0 and 1".to_string()),
+        Err(e) => Err(e.to_string()),
+    }
+}
+
+fn parse_non_zero(s: &str) -> Result<usize, String> {
+    match s.parse::<usize>() {
+        Ok(0) => Err("Value must be greater than 0".to_string()),
+        Ok(n) => Ok(n),
+        Err(e) => Err(e.to_string()),
+    }
+}
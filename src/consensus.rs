@@ -0,0 +1,239 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::ops::Range;
+use anyhow::{Result, Context};
+use noodles::fasta;
+use noodles::fasta::record::{Definition, Sequence};
+use crate::cli::{ConsensusTie, NameField};
+use crate::reference::Reference;
+use crate::sidecar;
+
+/// Symbols tallied at each column, in tally order
+const SYMBOLS: [u8; 6] = *b"ACGTN-";
+
+/// Per-column symbol tallies for a set of aligned rows
+#[derive(Default)]
+pub struct ColumnCounts {
+    counts: Vec<[usize; 6]>,
+}
+
+impl ColumnCounts {
+    /// Add an aligned row to the tallies, anything that isn't ACGT or a gap (`-` or `.`) counts as N
+    pub fn add(&mut self, row: &[u8]) {
+        self.add_span(row, 0..row.len());
+    }
+
+    /// Add only the `columns` of a row the read covers, so terminal padding doesn't vote for a gap
+    pub fn add_span(&mut self, row: &[u8], columns: Range<usize>) {
+        if self.counts.len() < row.len() {
+            self.counts.resize(row.len(), [0; 6]);
+        }
+        let end = columns.end.min(row.len());
+        let start = columns.start.min(end);
+        for (column, base) in self.counts[start..end].iter_mut().zip(&row[start..end]) {
+            let index = match base.to_ascii_uppercase() {
+                b'A' => 0,
+                b'C' => 1,
+                b'G' => 2,
+                b'T' => 3,
+                b'-' | b'.' => 5,
+                _ => 4,
+            };
+            column[index] += 1;
+        }
+    }
+
+    /// Tallies of a column in `ACGTN-` order, all zero past the last row
+    pub fn column(&self, index: usize) -> [usize; 6] {
+        self.counts.get(index).copied().unwrap_or_default()
+    }
+
+    /// Majority symbol at each column, N where the top count is tied
+    pub fn consensus(&self) -> Vec<u8> {
+        self.consensus_with(ConsensusTie::N, None)
+    }
+
+    /// Majority symbol at each column, with ties broken by `tie`
+    /// `reference` gives the base for reference tie breaking, columns past its end fall back to N
+    pub fn consensus_with(&self, tie: ConsensusTie, reference: Option<&[u8]>) -> Vec<u8> {
+        self.counts.iter().enumerate().map(|(index, column)| {
+            let (symbol, _) = column_consensus(column);
+            if symbol != TIED {
+                return symbol;
+            }
+            match tie {
+                ConsensusTie::N => b'N',
+                ConsensusTie::Ref => reference.and_then(|reference| reference.get(index)).copied().unwrap_or(b'N'),
+                ConsensusTie::Iupac => iupac_code(column),
+            }
+        }).collect()
+    }
+
+    /// Mean fraction of bases agreeing with the consensus base, over columns whose consensus is a base
+    /// Gaps don't count for or against a column, so partial reads don't drag the identity down
+    pub fn identity_to_consensus(&self) -> Option<(f64, usize)> {
+        let mut total = 0.0;
+        let mut columns = 0;
+        for column in &self.counts {
+            let (symbol, count) = column_consensus(column);
+            let bases: usize = column[..5].iter().sum();
+            if matches!(symbol, TIED | b'-' | b'N') || bases == 0 {
+                continue;
+            }
+            total += count as f64 / bases as f64;
+            columns += 1;
+        }
+        (columns > 0).then(|| (total / columns as f64, columns))
+    }
+
+    /// Majority symbol at each column with the fraction of rows supporting it
+    pub fn support(&self) -> Vec<(u8, f64)> {
+        self.counts.iter().map(|column| {
+            let (symbol, count) = column_consensus(column);
+            let symbol = if symbol == TIED { b'N' } else { symbol };
+            let depth: usize = column.iter().sum();
+            let fraction = if depth == 0 { 0.0 } else { count as f64 / depth as f64 };
+            (symbol, fraction)
+        }).collect()
+    }
+}
+
+/// Marks a column whose top count is shared by more than one symbol
+const TIED: u8 = 0;
+
+/// Majority symbol of a column and its count, `TIED` when the top count is shared
+fn column_consensus(column: &[usize; 6]) -> (u8, usize) {
+    let max = *column.iter().max().unwrap_or(&0);
+    if max == 0 {
+        return (b'-', 0);
+    }
+    let mut winners = column.iter().enumerate().filter(|(_, &count)| count == max);
+    match (winners.next(), winners.next()) {
+        (Some((index, _)), None) => (SYMBOLS[index], max),
+        _ => (TIED, max),
+    }
+}
+
+/// IUPAC ambiguity code for the bases tied at the top of a column
+/// Ties involving N or a gap have no code, so give N
+fn iupac_code(column: &[usize; 6]) -> u8 {
+    let max = *column.iter().max().unwrap_or(&0);
+    let tied = |index: usize| column[index] == max;
+    if tied(4) || tied(5) {
+        return b'N';
+    }
+    match (tied(0), tied(1), tied(2), tied(3)) {
+        (true, false, true, false) => b'R',
+        (false, true, false, true) => b'Y',
+        (false, true, true, false) => b'S',
+        (true, false, false, true) => b'W',
+        (false, false, true, true) => b'K',
+        (true, true, false, false) => b'M',
+        (false, true, true, true) => b'B',
+        (true, false, true, true) => b'D',
+        (true, true, false, true) => b'H',
+        (true, true, true, false) => b'V',
+        _ => b'N',
+    }
+}
+
+/// Consensus group of a read, the group taken from its name alongside the contig it aligned to
+/// so reads on different contigs never share columns
+#[derive(Clone, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct GroupKey {
+    pub target: String,
+    pub group: String,
+}
+
+/// Extract the group key from a read name and its target
+/// All reads on a contig share a single group when no name field is given
+pub fn group_key(name: &[u8], target: &str, field: Option<&NameField>) -> GroupKey {
+    let group = match field {
+        Some(field) => field.extract(&String::from_utf8_lossy(name)),
+        None => "consensus".to_string(),
+    };
+    GroupKey { target: target.to_string(), group }
+}
+
+/// Groups sorted by contig then group, the contig is only added to record names when reads hit more than one
+fn sorted_groups(groups: &HashMap<GroupKey, ColumnCounts>) -> Vec<(String, &GroupKey)> {
+    let mut keys: Vec<&GroupKey> = groups.keys().collect();
+    keys.sort();
+    let several_targets = keys.windows(2).any(|pair| pair[0].target != pair[1].target);
+    keys.into_iter().map(|key| {
+        let name = if several_targets { format!("{}_{}", key.group, key.target) } else { key.group.clone() };
+        (name, key)
+    }).collect()
+}
+
+/// Write one consensus record per group, sorted by contig and group name
+/// Ties broken by the reference, and gap columns when `fill_reference` is set, take the base of the group's contig
+pub fn write_consensus(
+    path: &str,
+    groups: &HashMap<GroupKey, ColumnCounts>,
+    reference: &Reference,
+    fill_reference: bool,
+    tie: ConsensusTie,
+) -> Result<()> {
+    let file = File::create(path).context("Failed to create consensus file")?;
+    let mut writer = fasta::Writer::new(BufWriter::new(file));
+
+    for (name, key) in sorted_groups(groups) {
+        let contig = reference.get(&key.target).map(|contig| contig.sequence.as_slice());
+        let definition = Definition::new(name.into_bytes(), Some(format!("reference={}", key.target).into_bytes()));
+        let mut consensus = groups[key].consensus_with(tie, contig);
+        if let Some(contig) = contig.filter(|_| fill_reference) {
+            for (base, reference_base) in consensus.iter_mut().zip(contig) {
+                if *base == b'-' {
+                    *base = *reference_base;
+                }
+            }
+            // Columns past the last row are missing from the tallies entirely
+            consensus.extend_from_slice(contig.get(consensus.len()..).unwrap_or_default());
+        }
+        let sequence = Sequence::from(consensus);
+        writer.write_record(&fasta::Record::new(definition, sequence))
+            .context("Failed to write consensus record")?;
+    }
+    Ok(())
+}
+
+/// Write the consensus symbol and its supporting fraction for every column as TSV
+pub fn write_consensus_freq(path: &str, groups: &HashMap<GroupKey, ColumnCounts>, coordinate_base: u8) -> Result<()> {
+    let file = File::create(path).context("Failed to create consensus frequency file")?;
+    let mut writer = BufWriter::new(file);
+    writeln!(writer, "group\treference\tcolumn\tbase\tsupport")?;
+
+    for (name, key) in sorted_groups(groups) {
+        for (column, (symbol, fraction)) in groups[key].support().into_iter().enumerate() {
+            writeln!(writer, "{}\t{}\t{}\t{}\t{:.4}", name, key.target, sidecar::position(column, coordinate_base), symbol as char, fraction)?;
+        }
+    }
+    writer.flush().context("Failed to write consensus frequency file")?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn a_g_tie_gives_r() {
+        assert_eq!(iupac_code(&[2, 0, 2, 0, 0, 0]), b'R');
+    }
+
+    #[test]
+    fn iupac_tie_breaking_calls_ambiguity_codes() {
+        let mut counts = ColumnCounts::default();
+        counts.add(b"AC");
+        counts.add(b"GT");
+        assert_eq!(counts.consensus_with(ConsensusTie::Iupac, None), b"RY");
+        assert_eq!(counts.consensus(), b"NN");
+    }
+
+    #[test]
+    fn ties_with_gaps_give_n() {
+        assert_eq!(iupac_code(&[1, 0, 0, 0, 0, 1]), b'N');
+    }
+}
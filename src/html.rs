@@ -0,0 +1,56 @@
+use std::io::Write;
+use anyhow::{Result, Context};
+use crate::rows::RowStore;
+
+const STYLE: &str = "body { font-family: sans-serif; }
+table { border-collapse: collapse; font-family: monospace; }
+td { padding: 0 1px; text-align: center; }
+td.name { text-align: left; padding-right: 1em; white-space: nowrap; }
+.A { background: #8fd18f; } .C { background: #8fb3e6; } .G { background: #f0d27a; } .T { background: #ee9a9a; }
+.N { background: #d8d8d8; } .gap { color: #aaaaaa; background: #f2f2f2; }
+.mismatch { outline: 2px solid #c00000; font-weight: bold; }";
+
+/// Write the alignment as a self-contained HTML table, one row per read with each base coloured
+/// Bases that differ from `reference` are highlighted, and the reference is shown as the first row when given
+pub fn write_html<W: Write>(writer: &mut W, rows: &mut RowStore, reference: Option<&[u8]>) -> Result<()> {
+    writeln!(writer, "<!DOCTYPE html>")?;
+    writeln!(writer, "<html><head><meta charset=\"utf-8\"><title>fastalign alignment</title>")?;
+    writeln!(writer, "<style>\n{}\n</style></head><body>", STYLE)?;
+    writeln!(writer, "<table>")?;
+    if let Some(reference) = reference {
+        write_row(writer, b"reference", reference, None)?;
+    }
+    for index in 0..rows.count() {
+        let name = rows.names()[index].clone();
+        write_row(writer, &name, &rows.row(index)?, reference)?;
+    }
+    writeln!(writer, "</table>\n</body></html>")?;
+    writer.flush().context("Failed to write HTML alignment")?;
+    Ok(())
+}
+
+fn write_row<W: Write>(writer: &mut W, name: &[u8], row: &[u8], reference: Option<&[u8]>) -> Result<()> {
+    write!(writer, "<tr><td class=\"name\">{}</td>", escape(&String::from_utf8_lossy(name)))?;
+    for (column, &base) in row.iter().enumerate() {
+        let class = match base.to_ascii_uppercase() {
+            b'A' => "A",
+            b'C' => "C",
+            b'G' => "G",
+            b'T' => "T",
+            b'-' | b'.' => "gap",
+            _ => "N",
+        };
+        let mismatch = class != "gap" && reference
+            .and_then(|reference| reference.get(column))
+            .is_some_and(|reference_base| !reference_base.eq_ignore_ascii_case(&base));
+        let class = if mismatch { format!("{} mismatch", class) } else { class.to_string() };
+        write!(writer, "<td class=\"{}\">{}</td>", class, escape(&(base as char).to_string()))?;
+    }
+    writeln!(writer, "</tr>")?;
+    Ok(())
+}
+
+/// Escape the characters HTML treats specially
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;").replace('"', "&quot;")
+}
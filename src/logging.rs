@@ -0,0 +1,50 @@
+use log::{Level, LevelFilter, Log, Metadata, Record};
+
+/// Target for output a flag explicitly asked for, like --profile, shown unless --quiet
+pub const REPORT: &str = "report";
+
+/// Log a report line, printed without a level prefix at the default verbosity
+macro_rules! report {
+    ($($arg:tt)*) => {
+        log::warn!(target: $crate::logging::REPORT, $($arg)*)
+    };
+}
+pub(crate) use report;
+
+/// Writes log records to stderr, prefixed with their level
+struct StderrLogger;
+
+impl Log for StderrLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= log::max_level()
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        match record.level() {
+            _ if record.target() == REPORT => eprintln!("{}", record.args()),
+            Level::Error => eprintln!("Error: {}", record.args()),
+            Level::Warn => eprintln!("Warning: {}", record.args()),
+            Level::Info => eprintln!("{}", record.args()),
+            level => eprintln!("[{}] {}", level, record.args()),
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+/// Install the stderr logger, --quiet keeps only errors and each -v adds a level past warnings
+pub fn init(quiet: bool, verbose: u8) {
+    let level = match (quiet, verbose) {
+        (true, _) => LevelFilter::Error,
+        (false, 0) => LevelFilter::Warn,
+        (false, 1) => LevelFilter::Info,
+        (false, 2) => LevelFilter::Debug,
+        _ => LevelFilter::Trace,
+    };
+    // Only fails if a logger is already installed, which leaves that one in place
+    let _ = log::set_logger(&StderrLogger);
+    log::set_max_level(level);
+}
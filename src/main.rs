@@ -1,17 +1,55 @@
-use std::fs::File;
-use std::io::{BufReader, BufWriter, Write};
+use std::borrow::Cow;
+use std::io::Write;
 use std::str::FromStr;
-use clap::Parser;
 use minimap2::*;
 use noodles::fasta;
 use anyhow::{Result, Context, anyhow};
 use noodles::fasta::Record;
 use noodles::fasta::record::{Definition, Sequence};
+use std::sync::Mutex;
 use std::thread;
-use crossbeam_channel::{unbounded};
+use std::time::Instant;
+use crossbeam_channel::{bounded, unbounded, Receiver, Sender};
 
+mod alphabet;
+mod bundle;
 mod cli;
+mod consensus;
+mod html;
+mod logging;
+mod matrix;
+mod mm2_opts;
+mod output;
+mod pairwise;
+mod pileup;
+mod pretty;
+mod primers;
+mod profile;
+mod reader;
+mod reference;
+mod rows;
+mod stats;
+mod stockholm;
+mod sidecar;
+mod summary;
+mod translate;
+mod variants;
+mod writer;
 
+/// minimap2 index flag for homopolymer-compressed minimizers (`-H`)
+const MM_I_HPC: i16 = 0x1;
+/// minimap2 mapping flag for computing CIGARs, set by `with_cigar`
+const MM_F_CIGAR: i64 = 0x004;
+
+/// Number of reads at the start of the input whose lengths are compared to the reference
+const READ_LENGTH_SAMPLE: usize = 100;
+/// How many times longer than the longest contig the median read must be to trigger a warning
+const READ_LENGTH_WARNING_RATIO: usize = 2;
+
+/// Exit code for a run that completed without errors but mapped no reads
+const EXIT_NO_READS_MAPPED: i32 = 2;
+
+#[derive(Clone, Copy, Debug)]
 enum CigarOperation {
     Match(usize),
     Insertion(usize),
@@ -29,12 +67,19 @@ impl FromStr for CigarOperation {
     type Err = anyhow::Error;
 
     fn from_str(cigar_operation: &str) -> Result<Self, Self::Err> {
-        // split the string into the count and operation
+        // split the string into the count and operation, splitting on the last character
+        // rather than the last byte so multibyte input errors rather than panics
+        let (split_at, operation) = cigar_operation.char_indices().last()
+            .context(format!("Failed to parse CIGAR operation character: {}", cigar_operation))?;
         // count = number of times to apply the operation
-        let count = cigar_operation[..cigar_operation.len()-1].parse::<usize>()
+        let count_str = &cigar_operation[..split_at];
+        if count_str.is_empty() || !count_str.bytes().all(|b| b.is_ascii_digit()) {
+            return Err(anyhow!("Failed to parse CIGAR operation count: {}", cigar_operation));
+        }
+        let count = count_str.parse::<usize>()
             .context(format!("Failed to parse CIGAR operation count: {}", cigar_operation))?;
         // operation = the type of operation to apply
-        match cigar_operation.chars().last().context(format!("Failed to parse CIGAR operation character: {}", cigar_operation))? {
+        match operation {
             'M' => Ok(CigarOperation::Match(count)),
             'I' => Ok(CigarOperation::Insertion(count)),
             'D' => Ok(CigarOperation::Deletion(count)),
@@ -54,18 +99,133 @@ impl FromStr for CigarOperation {
 }
 
 /// Split the cigar into individual operations and parse
+/// Every operation ends at the first non-digit character, which also covers `=`
 fn parse_cigar(cigar_string: &str) -> Result<Vec<CigarOperation>> {
-    cigar_string.split_inclusive(char::is_alphabetic)
+    cigar_string.split_inclusive(|c: char| !c.is_ascii_digit())
         .map(|cigar_operation| cigar_operation.parse().with_context(|| format!("Failed to parse CIGAR operation: {}", cigar_operation)))
         .collect()
 }
 
-/// Build an aligned sequence from the CIGAR string
-fn align_sequence(sequence: &[u8], reference_len: usize, cigar: &str, aln_start: i32) -> Result<Vec<u8>> {
+/// Split `M` operations into `=` and `X` runs by comparing the read and reference bases
+/// Operations running off either sequence are left as they are
+fn resolve_matches(cigar: &[CigarOperation], query: &[u8], reference: &[u8], ref_start: usize) -> Vec<CigarOperation> {
+    let mut resolved = Vec::with_capacity(cigar.len());
+    let mut seq_pos = 0;
+    let mut ref_pos = ref_start;
+    for op in cigar {
+        match *op {
+            CigarOperation::Match(count) => {
+                let bases = query.get(seq_pos..seq_pos + count).zip(reference.get(ref_pos..ref_pos + count));
+                let Some((read_bases, ref_bases)) = bases else {
+                    resolved.push(CigarOperation::Match(count));
+                    seq_pos += count;
+                    ref_pos += count;
+                    continue;
+                };
+                for (read_base, ref_base) in read_bases.iter().zip(ref_bases) {
+                    let equal = read_base.eq_ignore_ascii_case(ref_base);
+                    match resolved.last_mut() {
+                        Some(CigarOperation::Equal(run)) if equal => *run += 1,
+                        Some(CigarOperation::Diff(run)) if !equal => *run += 1,
+                        _ if equal => resolved.push(CigarOperation::Equal(1)),
+                        _ => resolved.push(CigarOperation::Diff(1)),
+                    }
+                }
+                seq_pos += count;
+                ref_pos += count;
+            }
+            CigarOperation::Equal(count) | CigarOperation::Diff(count) => {
+                resolved.push(*op);
+                seq_pos += count;
+                ref_pos += count;
+            }
+            CigarOperation::Insertion(count) | CigarOperation::SoftClip(count) => {
+                resolved.push(*op);
+                seq_pos += count;
+            }
+            CigarOperation::Deletion(count) | CigarOperation::Skipped(count) => {
+                resolved.push(*op);
+                ref_pos += count;
+            }
+            _ => resolved.push(*op),
+        }
+    }
+    resolved
+}
+
+/// Bases present in a read but not the reference, which are dropped from the aligned row
+struct Insertion {
+    /// 0-based offset of the reference base the insertion comes before
+    ref_pos: usize,
+    bases: Vec<u8>,
+}
+
+/// Where a read aligned along its own length, as reported in PAF
+#[derive(Default)]
+struct QuerySpan {
+    len: usize,
+    start: usize,
+    end: usize,
+    residue_matches: usize,
+    block_len: usize,
+}
+
+/// One piece of a read that aligned in several places
+struct Segment {
+    target: String,
+    start: usize,
+    end: usize,
+    strand: char,
+    query_start: usize,
+    query_end: usize,
+}
+
+impl Segment {
+    fn new(aln: &Mapping) -> Self {
+        Segment {
+            target: aln.target_name.as_ref().map_or_else(|| "*".to_string(), |target| target.to_string()),
+            start: aln.target_start as usize,
+            end: aln.target_end as usize,
+            strand: strand_symbol(&aln.strand),
+            query_start: aln.query_start as usize,
+            query_end: aln.query_end as usize,
+        }
+    }
+}
+
+impl QuerySpan {
+    /// Fraction of the alignment block that matches, as minimap2 reports it
+    fn identity(&self) -> f64 {
+        if self.block_len > 0 { self.residue_matches as f64 / self.block_len as f64 } else { 0.0 }
+    }
+}
+
+/// An aligned output record along with what was learnt while aligning it
+struct AlignedRead {
+    record: fasta::Record,
+    target: String,
+    /// 0-based, end exclusive span of the alignment on the target
+    start: usize,
+    end: usize,
+    strand: char,
+    mapq: u32,
+    query: QuerySpan,
+    insertions: Vec<Insertion>,
+    stats: Option<stats::ReadStats>,
+    clips: Option<Clips>,
+    /// Every piece of a chimeric read when --detect-chimera is set, empty otherwise
+    segments: Vec<Segment>,
+    mapped: bool,
+}
+
+/// Build an aligned sequence from the CIGAR string, returning the row and the insertions dropped from it
+/// Deletions are filled with the reference bases instead of gaps when `fill_deletions` is set
+fn align_sequence(sequence: &[u8], reference: &[u8], reference_len: usize, cigar: &str, aln_start: i32, fill_deletions: bool) -> Result<(Vec<u8>, Vec<Insertion>)> {
     let mut aligned_seq = Vec::with_capacity(reference_len);
     // Add gaps for any reference bases before the start of the alignment
-    aligned_seq.extend("-".repeat(aln_start as usize).bytes());
+    aligned_seq.resize(aln_start as usize, b'-');
 
+    let mut insertions = Vec::new();
     let mut seq_pos = 0;
     let mut ref_pos = aln_start as usize;
 
@@ -88,11 +248,26 @@ fn align_sequence(sequence: &[u8], reference_len: usize, cigar: &str, aln_start:
                 seq_pos += count;
                 ref_pos += count;
             },
-            // Insertions are ignored, so just increment the sequence position
-            CigarOperation::Insertion(count) => seq_pos += count,
+            // Insertions are left out of the row, but kept so they can be reported
+            CigarOperation::Insertion(count) => {
+                let bases = sequence.get(seq_pos..seq_pos + count).ok_or_else(|| anyhow!(
+                    "CIGAR operation out-of-bounds sequence: seq_pos={}, count={}, sequence length={}",
+                    seq_pos, count, sequence.len()
+                ))?;
+                insertions.push(Insertion { ref_pos, bases: bases.to_vec() });
+                seq_pos += count;
+            },
             // Might get Ns in CIGAR?
             CigarOperation::Deletion(count) | CigarOperation::Skipped(count) => {
-                aligned_seq.extend("-".repeat(count).bytes());
+                if fill_deletions {
+                    let ref_bases = reference.get(ref_pos..ref_pos + count).ok_or_else(|| anyhow!(
+                        "CIGAR operation out-of-bounds reference: ref_pos={}, count={}, reference length={}",
+                        ref_pos, count, reference.len()
+                    ))?;
+                    aligned_seq.extend_from_slice(ref_bases);
+                } else {
+                    aligned_seq.resize(aligned_seq.len() + count, b'-');
+                }
                 ref_pos += count;
             },
             // TODO: Soft and hard clips are ignored for now, decide how to handle them
@@ -101,138 +276,1098 @@ fn align_sequence(sequence: &[u8], reference_len: usize, cigar: &str, aln_start:
         }
     }
 
+    // Consuming more reference than exists means the alignment coordinates and reference disagree
+    let target_len = reference.len().min(reference_len);
+    if ref_pos > target_len {
+        return Err(anyhow!(
+            "CIGAR operation out-of-bounds reference: alignment ends at {} but reference length is {}",
+            ref_pos, target_len
+        ));
+    }
+
     // Add gaps for any reference bases after the end of the alignment
     if ref_pos < reference_len {
-        aligned_seq.extend("-".repeat(reference_len - ref_pos).bytes());
+        aligned_seq.resize(aligned_seq.len() + reference_len - ref_pos, b'-');
+    }
+
+    Ok((aligned_seq, insertions))
+}
+
+/// Check the aligned row and its dropped insertions give back exactly the aligned part of the read
+fn verify_roundtrip(aligned_seq: &[u8], insertions: &[Insertion], aln_start: usize, aln_end: usize, query: &[u8]) -> Result<()> {
+    let span = aligned_seq.get(aln_start..aln_end)
+        .ok_or_else(|| anyhow!("aligned span {}..{} is outside the row of length {}", aln_start, aln_end, aligned_seq.len()))?;
+    let mut insertions = insertions.iter().peekable();
+    let mut rebuilt = Vec::with_capacity(query.len());
+    for (pos, &base) in (aln_start..).zip(span) {
+        while let Some(insertion) = insertions.next_if(|insertion| insertion.ref_pos <= pos) {
+            rebuilt.extend_from_slice(&insertion.bases);
+        }
+        if base != b'-' {
+            rebuilt.push(base);
+        }
+    }
+    for insertion in insertions {
+        rebuilt.extend_from_slice(&insertion.bases);
+    }
+    if rebuilt != query {
+        let diverges = rebuilt.iter().zip(query).take_while(|(a, b)| a == b).count();
+        return Err(anyhow!(
+            "rebuilt {} bases but the read has {}, first differing at read position {}",
+            rebuilt.len(), query.len(), diverges
+        ));
+    }
+    Ok(())
+}
+
+/// Left-align deletions within the aligned span of a row
+/// Only deletions need normalising as insertions are not present in the output
+fn normalize_indels(aligned_seq: &mut [u8], reference: &[u8], aln_start: usize, aln_end: usize) {
+    let aln_end = aln_end.min(aligned_seq.len()).min(reference.len());
+    let mut pos = aln_start;
+
+    while pos < aln_end {
+        if aligned_seq[pos] != b'-' {
+            pos += 1;
+            continue;
+        }
+        let mut gap_start = pos;
+        let mut gap_end = pos;
+        while gap_end < aln_end && aligned_seq[gap_end] == b'-' {
+            gap_end += 1;
+        }
+        pos = gap_end;
+
+        // A gap can move one base left when the reference base before it equals the last
+        // reference base it covers - the read base then lines up with an identical reference base
+        while gap_start > aln_start
+            && aligned_seq[gap_start - 1] != b'-'
+            && reference[gap_start - 1].eq_ignore_ascii_case(&reference[gap_end - 1])
+        {
+            aligned_seq[gap_end - 1] = aligned_seq[gap_start - 1];
+            aligned_seq[gap_start - 1] = b'-';
+            gap_start -= 1;
+            gap_end -= 1;
+        }
+    }
+}
+
+/// Fill gap runs of at most `max_len` within the aligned span of a row with the reference bases
+/// Longer deletions are kept, as are the gaps padding the row outside the alignment
+fn fill_short_gaps(aligned_seq: &mut [u8], reference: &[u8], aln_start: usize, aln_end: usize, max_len: usize) {
+    let aln_end = aln_end.min(aligned_seq.len()).min(reference.len());
+    let mut pos = aln_start;
+
+    while pos < aln_end {
+        if aligned_seq[pos] != b'-' {
+            pos += 1;
+            continue;
+        }
+        let gap_start = pos;
+        while pos < aln_end && aligned_seq[pos] == b'-' {
+            pos += 1;
+        }
+        if pos - gap_start <= max_len {
+            aligned_seq[gap_start..pos].copy_from_slice(&reference[gap_start..pos]);
+        }
+    }
+}
+
+/// Mark the padding outside the aligned span with `.`, leaving `-` for deletions within it
+fn mark_uncovered(aligned_seq: &mut [u8], aln_start: usize, aln_end: usize) {
+    let aln_end = aln_end.min(aligned_seq.len());
+    let aln_start = aln_start.min(aln_end);
+    let (before, rest) = aligned_seq.split_at_mut(aln_start);
+    let after = &mut rest[aln_end - aln_start..];
+    for base in before.iter_mut().chain(after.iter_mut()).filter(|base| **base == b'-') {
+        *base = b'.';
     }
+}
+
+/// Replace low confidence aligned bases with N, leaving deletion gaps untouched
+/// The whole row is masked when the alignment MAPQ is below `min_mapq`, otherwise only the
+/// `flank` aligned bases next to a clipped end are masked
+/// `clipped` gives whether the read was clipped at the left and right of the row, in reference orientation
+fn mask_low_quality(aligned_seq: &mut [u8], clipped: (bool, bool), aln_start: usize, aln_end: usize, mapq: u32, flank: usize, min_mapq: u32) {
+    let aln_end = aln_end.min(aligned_seq.len());
+    if aln_start >= aln_end {
+        return;
+    }
+    let span = &mut aligned_seq[aln_start..aln_end];
+
+    if mapq < min_mapq {
+        span.iter_mut().filter(|b| **b != b'-').for_each(|b| *b = b'N');
+        return;
+    }
+
+    let (clipped_start, clipped_end) = clipped;
+    if clipped_start {
+        span.iter_mut().filter(|b| **b != b'-').take(flank).for_each(|b| *b = b'N');
+    }
+    if clipped_end {
+        span.iter_mut().rev().filter(|b| **b != b'-').take(flank).for_each(|b| *b = b'N');
+    }
+}
+
+/// Whether the read was clipped at the left and right ends of its row
+/// minimap2 CIGARs carry no clip operations, so this comes from the query span, which is on the forward read
+fn clipped_ends(aln: &Mapping, read_len: usize) -> (bool, bool) {
+    let clipped_five_prime = aln.query_start > 0;
+    let clipped_three_prime = (aln.query_end as usize) < read_len;
+    match aln.strand {
+        Strand::Forward => (clipped_five_prime, clipped_three_prime),
+        // Reverse strand rows hold the reverse complement, so the read's 3' end is on the left
+        Strand::Reverse => (clipped_three_prime, clipped_five_prime),
+    }
+}
+
+/// Percentage of aligned columns that are matches
+fn percent_identity(aln: &Mapping) -> f64 {
+    if aln.block_len > 0 {
+        aln.match_len as f64 / aln.block_len as f64 * 100.0
+    } else {
+        0.0
+    }
+}
+
+/// Strand symbol used in the output formats
+fn strand_symbol(strand: &Strand) -> char {
+    match strand {
+        Strand::Forward => '+',
+        Strand::Reverse => '-',
+    }
+}
+
+/// Aligned part of the read in the orientation its CIGAR refers to
+/// minimap2 leaves the unaligned ends out of the CIGAR and reports reverse strand CIGARs
+/// against the reverse complement of the read, while the query coordinates are always on the forward read
+fn aligned_query<'a>(sequence: &'a [u8], aln: &Mapping) -> Cow<'a, [u8]> {
+    let (start, end) = (aln.query_start as usize, aln.query_end as usize);
+    match aln.strand {
+        Strand::Forward => Cow::Borrowed(&sequence[start..end]),
+        Strand::Reverse => Cow::Owned(alphabet::reverse_complement(&sequence[start..end])),
+    }
+}
+
+/// Ends of a read left unaligned by minimap2, in the read's own orientation
+struct Clips {
+    five_prime: Vec<u8>,
+    three_prime: Vec<u8>,
+}
+
+impl Clips {
+    fn new(sequence: &[u8], aln: &Mapping) -> Self {
+        Clips {
+            five_prime: sequence[..aln.query_start as usize].to_vec(),
+            three_prime: sequence[aln.query_end as usize..].to_vec(),
+        }
+    }
+}
+
+/// All-gap placeholder row for a read that did not map
+fn unmapped_read(record: &fasta::Record, reference: &reference::Reference, args: &cli::RunArgs) -> AlignedRead {
+    let definition = Definition::new(record.name().to_owned(), None);
+    let padding = if args.distinguish_coverage { b'.' } else { b'-' };
+    // Translated rows hold one residue per codon of the frame
+    let row_len = if args.translate {
+        reference.max_len().saturating_sub(args.frame as usize) / 3
+    } else {
+        reference.max_len()
+    };
+    let sequence = Sequence::from(vec![padding; row_len]);
+    AlignedRead {
+        record: Record::new(definition, sequence),
+        target: "*".to_string(),
+        start: 0,
+        end: 0,
+        strand: '+',
+        mapq: 0,
+        query: QuerySpan { len: record.sequence().len(), ..Default::default() },
+        insertions: Vec::new(),
+        stats: None,
+        clips: None,
+        segments: Vec::new(),
+        mapped: false,
+    }
+}
+
+/// Replace soft-masked (lowercase) read bases with N so they can't count as matches
+fn mask_lowercase(record: Record) -> Record {
+    if !record.sequence().as_ref().iter().any(u8::is_ascii_lowercase) {
+        return record;
+    }
+    let sequence: Vec<u8> = record.sequence().as_ref().iter()
+        .map(|&base| if base.is_ascii_lowercase() { b'N' } else { base })
+        .collect();
+    Record::new(record.definition().clone(), Sequence::from(sequence))
+}
 
-    Ok(aligned_seq)
+/// Pick the primary alignment, falling back to the highest scoring secondary when none is marked primary
+/// There should only be one primary, if there are several the highest scoring is used unless `strict` is set,
+/// which also makes an all-secondary hit list an error
+fn select_alignment<'a>(alignment: &'a [Mapping], name: &[u8], strict: bool) -> Result<Option<&'a Mapping>> {
+    let primaries: Vec<&Mapping> = alignment.iter().filter(|aln| aln.is_primary).collect();
+    if primaries.len() > 1 {
+        if strict {
+            return Err(anyhow!("{} primary alignments reported for {}", primaries.len(), String::from_utf8_lossy(name)));
+        }
+        log::warn!("{} primary alignments reported for {}, using the highest scoring", primaries.len(), String::from_utf8_lossy(name));
+    }
+    let candidates = if primaries.is_empty() && !alignment.is_empty() {
+        if strict {
+            return Err(anyhow!("Only secondary alignments reported for {}", String::from_utf8_lossy(name)));
+        }
+        log::warn!("Only secondary alignments reported for {}, using the highest scoring", String::from_utf8_lossy(name));
+        alignment.iter().collect()
+    } else {
+        primaries
+    };
+    let score = |aln: &&Mapping| aln.alignment.as_ref().and_then(|a| a.alignment_score).unwrap_or(0);
+    // max_by_key returns the last of equal maxima, so iterate in reverse to keep minimap2's order on ties
+    Ok(candidates.into_iter().rev().max_by_key(score))
 }
 
+/// Whether the alignment spans less of its contig than --min-ref-coverage
+fn below_min_coverage(aligned_read: &AlignedRead, reference: &reference::Reference, min_coverage: Option<f64>) -> bool {
+    let (Some(min_coverage), Some(contig)) = (min_coverage, reference.get(&aligned_read.target)) else {
+        return false;
+    };
+    let span = aligned_read.end - aligned_read.start;
+    (span as f64) < min_coverage * contig.sequence.len() as f64
+}
+
+/// Whether the alignment consumes no reference bases, so every aligned base is clipped or inserted
+fn is_unanchored(aligned_read: &AlignedRead) -> bool {
+    match &aligned_read.stats {
+        Some(stats) => stats.reference_span() == 0,
+        None => aligned_read.end <= aligned_read.start,
+    }
+}
 
-fn align_record(record: &fasta::Record, reference: &str, aligner: &Aligner) -> Result<fasta::Record> {
+/// Align a record, returning no reads if it did not map to the reference
+/// With --split-chimera a chimeric read gives one read per segment, in read order
+fn align_record(record: &fasta::Record, reference: &reference::Reference, aligner: &Aligner, args: &cli::RunArgs) -> Result<Vec<AlignedRead>> {
     let seq = record.sequence();
     let name = record.name();
 
     let alignment = aligner.map(seq.as_ref(), false, false, None, None)
         .map_err(|e| anyhow!(e))
         .context("Failed to align sequence")?;
-    
-    // 
-
-    // alignment should always contain only 1 alignment, but if mapping fails, it might be empty
-    if let Some(aln) = alignment.first() {
-        if !aln.is_primary {
-            println!("Not a primary alignment: {}", std::str::from_utf8(name)?);
-        }
-        if let Some(cigar_string) = aln.alignment.as_ref().and_then(|a| a.cigar_str.as_ref()) {
-            let aligned_seq = align_sequence(
-                seq.as_ref(),
-                reference.len(),
-                cigar_string,
-                aln.target_start,
-            ).context("Failed to align sequence")?;
-
-            let definition = Definition::new(name.to_owned(), None);
-            let sequence = Sequence::from(aligned_seq);
-            Ok(Record::new(definition, sequence))
-        } else {
-            Err(anyhow!("No CIGAR string found for alignment {}", std::str::from_utf8(name)?))
+
+    // minimap2 marks the best scoring hit across all indexed targets as primary,
+    // so with several references this picks the best matching one
+    // if mapping fails the alignment might be empty
+    let Some(aln) = select_alignment(&alignment, name, args.strict_primary)? else {
+        log::debug!("{} did not map", String::from_utf8_lossy(name));
+        return Ok(Vec::new());
+    };
+    log::debug!(
+        "{} mapped to {}:{}-{} ({} hits)",
+        String::from_utf8_lossy(name), aln.target_name.as_ref().map_or("*", |target| target.as_str()), aln.target_start, aln.target_end, alignment.len()
+    );
+
+    // An empty CIGAR would align nothing and leave an all-gap row that looks like a real alignment
+    let has_cigar = aln.alignment.as_ref()
+        .and_then(|a| a.cigar_str.as_ref())
+        .is_some_and(|cigar| !cigar.is_empty());
+    if args.needs_cigar() && !has_cigar {
+        match args.empty_cigar {
+            cli::EmptyCigar::Error => {
+                return Err(anyhow!("Alignment for {} has an empty CIGAR", String::from_utf8_lossy(name)));
+            }
+            cli::EmptyCigar::Unmapped => {
+                log::warn!("Alignment for {} has an empty CIGAR, treating it as unmapped", String::from_utf8_lossy(name));
+                return Ok(Vec::new());
+            }
         }
+    }
+
+    // Supplementary alignments are the other pieces of a chimeric read
+    let mut segments: Vec<&Mapping> = std::iter::once(aln)
+        .chain(alignment.iter().filter(|other| other.is_supplementary && !std::ptr::eq(*other, aln)))
+        .collect();
+    segments.sort_by_key(|segment| segment.query_start);
+
+    let mut aligned_reads = if args.split_chimera && segments.len() > 1 {
+        segments.iter().enumerate().map(|(i, segment)| {
+            let segment_name = format!("{}_seg{}", String::from_utf8_lossy(name), i + 1).into_bytes();
+            build_aligned_read(record, &segment_name, segment, reference, args)
+        }).collect::<Result<Vec<_>>>()?
     } else {
-        Err(anyhow!("No alignment found for sequence {}", std::str::from_utf8(name)?))
+        vec![build_aligned_read(record, name, aln, reference, args)?]
+    };
+    if args.detect_chimera.is_some() && segments.len() > 1 {
+        aligned_reads[0].segments = segments.into_iter().map(Segment::new).collect();
     }
+    Ok(aligned_reads)
 }
 
+/// Build the output row for one alignment of a record, written under `name`
+fn build_aligned_read(record: &fasta::Record, name: &[u8], aln: &Mapping, reference: &reference::Reference, args: &cli::RunArgs) -> Result<AlignedRead> {
+    let seq = record.sequence();
+
+    let contig = aln.target_name.as_ref()
+        .and_then(|target| reference.get(target))
+        .ok_or_else(|| anyhow!("Unknown reference target for alignment {}", String::from_utf8_lossy(name)))?;
+
+    let mut aligned_read = AlignedRead {
+        record: Record::new(Definition::new(name.to_owned(), None), Sequence::from(Vec::new())),
+        target: contig.name.clone(),
+        start: aln.target_start as usize,
+        end: aln.target_end as usize,
+        strand: strand_symbol(&aln.strand),
+        mapq: aln.mapq,
+        query: QuerySpan {
+            len: seq.len(),
+            start: aln.query_start as usize,
+            end: aln.query_end as usize,
+            residue_matches: aln.match_len as usize,
+            block_len: aln.block_len as usize,
+        },
+        insertions: Vec::new(),
+        stats: None,
+        clips: args.clip_report.is_some().then(|| Clips::new(seq.as_ref(), aln)),
+        segments: Vec::new(),
+        mapped: true,
+    };
+
+    // The aligner isn't built with CIGARs when nothing needs them
+    if !args.needs_cigar() {
+        return Ok(aligned_read);
+    }
 
-fn process_fasta(input_path: &str, output_path: &str, reference: &str, aligner: &Aligner, num_threads: usize) -> Result<()> {
-    let input_file = File::open(input_path).context("Failed to open input file")?;
-    let mut input_reader = fasta::Reader::new(BufReader::new(input_file));
+    let cigar_string = aln.alignment.as_ref().and_then(|a| a.cigar_str.as_ref())
+        .ok_or_else(|| anyhow!("No CIGAR string found for alignment {}", String::from_utf8_lossy(name)))?;
 
-    let output_file = File::create(output_path).context("Failed to create output file")?;
-    let mut output_writer = fasta::Writer::new(BufWriter::new(output_file));
+    // Rows normally span their own contig, --pad-equal pads them all to the longest
+    let row_len = if args.pad_equal { reference.max_len() } else { contig.sequence.len() };
 
-    let (record_snd, record_recv) = unbounded();
-    let (aligned_snd, aligned_recv) = unbounded();
+    let query = aligned_query(seq.as_ref(), aln);
+
+    let mut cigar = parse_cigar(cigar_string)?;
+    if args.resolve_m {
+        cigar = resolve_matches(&cigar, &query, &contig.sequence, aligned_read.start);
+    }
+    let edit_distance = aln.alignment.as_ref().map(|a| a.nm);
+    aligned_read.stats = Some(stats::ReadStats::from_cigar(&cigar, edit_distance, row_len).with_read(seq.as_ref()));
+    let variants = args.variant_string
+        .then(|| variants::variant_string(&cigar, &query, &contig.sequence, aligned_read.start, args.coordinate_base, contig.offset));
+
+    // Coordinate-only formats don't need the aligned row
+    if args.out_format.is_coordinate_only() {
+        return Ok(aligned_read);
+    }
+
+    let (mut aligned_seq, insertions) = align_sequence(
+        &query,
+        &contig.sequence,
+        row_len,
+        cigar_string,
+        aln.target_start,
+        args.fill_deletions_with_ref,
+    ).context("Failed to align sequence")?;
+
+    if args.verify_roundtrip {
+        verify_roundtrip(&aligned_seq, &insertions, aligned_read.start, aligned_read.end, &query)
+            .with_context(|| format!("Round trip check failed for {}", String::from_utf8_lossy(name)))?;
+    }
+
+    if args.normalize_indels {
+        normalize_indels(&mut aligned_seq, &contig.sequence, aligned_read.start, aligned_read.end);
+    }
+
+    if let Some(max_len) = args.fill_short_gaps {
+        fill_short_gaps(&mut aligned_seq, &contig.sequence, aligned_read.start, aligned_read.end, max_len);
+    }
+
+    if args.mask_lowqual {
+        mask_low_quality(
+            &mut aligned_seq,
+            clipped_ends(aln, seq.len()),
+            aligned_read.start,
+            aligned_read.end,
+            aln.mapq,
+            args.mask_flank,
+            args.mask_min_mapq,
+        );
+    }
+
+    if args.distinguish_coverage {
+        mark_uncovered(&mut aligned_seq, aligned_read.start, aligned_read.end);
+    }
+
+    if args.no_pad {
+        aligned_seq.truncate(aligned_read.end);
+        aligned_seq.drain(..aligned_read.start);
+    }
+
+    if args.translate {
+        aligned_seq = translate::translate_row(&aligned_seq, args.frame as usize);
+    }
+
+    if args.restore_u {
+        alphabet::convert(&mut aligned_seq, alphabet::Alphabet::Rna);
+    }
+
+    let mut description = Vec::new();
+    // Record which reference was chosen when there was more than one to pick from
+    if args.reference.len() > 1 {
+        description.push(format!("reference={}", contig.name));
+    }
+    // Trimmed rows need their start to be placed back on the reference
+    if args.no_pad && !args.annotate_headers {
+        description.push(format!("start={}", sidecar::position(aligned_read.start + contig.offset, args.coordinate_base)));
+    }
+    if args.annotate_headers {
+        description.push(format!(
+            "target={} start={} strand={} mapq={} id={:.1}",
+            contig.source_name,
+            sidecar::position(aligned_read.start + contig.offset, args.coordinate_base),
+            aligned_read.strand,
+            aln.mapq,
+            percent_identity(aln),
+        ));
+    }
+    // Only possible when minimap2 reported secondary hits alone, see select_alignment
+    if !aln.is_primary && !aln.is_supplementary {
+        description.push("secondary=true".to_string());
+    }
+    if let Some(variants) = variants {
+        description.push(format!("variants={}", variants));
+    }
+    let description = (!description.is_empty()).then(|| description.join(" ").into_bytes());
+    let definition = Definition::new(name.to_owned(), description);
+    aligned_read.record = Record::new(definition, Sequence::from(aligned_seq));
+    aligned_read.insertions = insertions;
+    Ok(aligned_read)
+}
+
+
+/// Warn when the sampled reads are much longer than the reference, usually a sign --input and --reference are swapped
+fn check_read_lengths(lengths: &mut [usize], reference: &reference::Reference) {
+    if lengths.is_empty() {
+        return;
+    }
+    lengths.sort_unstable();
+    let median = lengths[lengths.len() / 2];
+    let reference_len = reference.max_len();
+    if median > reference_len * READ_LENGTH_WARNING_RATIO {
+        log::warn!(
+            "Reads are much longer than the reference (median {} vs {}), are --input and --reference swapped?",
+            median, reference_len
+        );
+    }
+}
+
+/// Align every input record and write the results, returning the number of reads that mapped
+fn process_fasta(args: &cli::RunArgs, reference: &reference::Reference, aligner: &Aligner) -> Result<usize> {
+    let mut input_reader = reader::InputReader::open(&args.input, args.sanitize_input, args.input_format)?;
+
+    let output_path = match args.chunk_output {
+        Some(_) => writer::chunk_path(&args.output, 1),
+        None => args.output.clone(),
+    };
+    let output_writer = if args.benchmark_mode {
+        writer::OutputWriter::Sink(std::io::sink())
+    } else {
+        writer::open_output(&output_path, args.bgzf, args.append, args.threads_io)?
+    };
+
+    let reference_alphabet = alphabet::detect_all(reference.contigs.iter().map(|contig| contig.sequence.as_slice()));
+
+    let query_dump = args.dump_queries.as_deref()
+        .map(|path| sidecar::create_sidecar(path).map(|file| Mutex::new(fasta::Writer::new(file))))
+        .transpose()?;
+    let names = args.names.as_deref().map(reference::parse_name_list).transpose()?;
+    let renames = args.rename_map.as_deref().map(reference::parse_rename_map).transpose()?;
+    let primers = args.primers.as_deref()
+        .map(|path| primers::Primers::load(path, args.primer_mismatches))
+        .transpose()?;
+
+    let profile = profile::Profile::default();
+    let summary = summary::Summary::default();
+    let start_time = Instant::now();
+
+    let (record_snd, record_recv) = channel(args.channel_capacity);
+    let (aligned_snd, aligned_recv) = channel(args.channel_capacity);
 
     thread::scope(|s| -> Result<()> {
-        // Spawn a thread to read the input FASTA file and send records to record_snd
-        s.spawn(|| -> Result<()> {
-            for record in input_reader.records() {
-                let record = record.context("Failed to read FASTA record")?;
-                record_snd.send(record).context("Failed to send record")?;
+        let mut handles = Vec::new();
+
+        // Spawn a thread to read the input FASTA file and send batches of records to record_snd
+        handles.push(s.spawn(|| -> Result<()> {
+            let mut batch = Vec::with_capacity(args.batch_size);
+            let mut read_index = 0;
+            // Records held back until the whole input is read when sorting by length
+            let mut sorted: Vec<(usize, Record)> = Vec::new();
+            let mut sampled_lengths = Vec::with_capacity(READ_LENGTH_SAMPLE);
+            let mut records = input_reader.records();
+            while let Some(record) = profile.time(profile::Stage::Read, || records.next()) {
+                let record = record.context("Failed to read input record")?;
+                // Reads not asked for are skipped before anything else looks at them
+                if names.as_ref().is_some_and(|names| !names.contains(String::from_utf8_lossy(record.name()).as_ref())) {
+                    continue;
+                }
+                profile.add_record(record.sequence().len());
+                if sampled_lengths.len() < READ_LENGTH_SAMPLE {
+                    sampled_lengths.push(record.sequence().len());
+                    if sampled_lengths.len() == READ_LENGTH_SAMPLE {
+                        check_read_lengths(&mut sampled_lengths, reference);
+                    }
+                }
+                if record.sequence().len() < args.min_read_len {
+                    summary::Summary::increment(&summary.too_short);
+                    continue;
+                }
+                if let Some(max_read_len) = args.max_read_len.filter(|&max| record.sequence().len() > max) {
+                    if args.fail_on_long_reads {
+                        return Err(anyhow!(
+                            "Read {} is {} bases, longer than --max-read-len {}",
+                            String::from_utf8_lossy(record.name()), record.sequence().len(), max_read_len
+                        ));
+                    }
+                    summary::Summary::increment(&summary.too_long);
+                    continue;
+                }
+                let record = if args.rna {
+                    let mut sequence = record.sequence().as_ref().to_vec();
+                    alphabet::convert(&mut sequence, alphabet::Alphabet::Dna);
+                    Record::new(record.definition().clone(), Sequence::from(sequence))
+                } else {
+                    record
+                };
+                let record = match (reference_alphabet, alphabet::detect(record.sequence().as_ref())) {
+                    (Some(expected), Some(found)) if expected != found => {
+                        if !args.convert_alphabet {
+                            return Err(anyhow!(
+                                "Read {} looks like {:?} but the reference looks like {:?}, use --convert-alphabet to convert reads",
+                                String::from_utf8_lossy(record.name()), found, expected
+                            ));
+                        }
+                        let mut sequence = record.sequence().as_ref().to_vec();
+                        alphabet::convert(&mut sequence, expected);
+                        Record::new(record.definition().clone(), Sequence::from(sequence))
+                    }
+                    _ => record,
+                };
+                if args.sort_by_length {
+                    sorted.push((read_index, record));
+                } else {
+                    batch.push((read_index, record));
+                    if batch.len() >= args.batch_size {
+                        let full_batch = std::mem::replace(&mut batch, Vec::with_capacity(args.batch_size));
+                        record_snd.send(full_batch).context("Failed to send record")?;
+                    }
+                }
+                read_index += 1;
+            }
+            if sampled_lengths.len() < READ_LENGTH_SAMPLE {
+                check_read_lengths(&mut sampled_lengths, reference);
+            }
+            // Longest reads go first so they don't leave one worker finishing long after the rest
+            sorted.sort_by_key(|(_, record)| std::cmp::Reverse(record.sequence().len()));
+            for item in sorted {
+                batch.push(item);
+                if batch.len() >= args.batch_size {
+                    let full_batch = std::mem::replace(&mut batch, Vec::with_capacity(args.batch_size));
+                    record_snd.send(full_batch).context("Failed to send record")?;
+                }
+            }
+            if !batch.is_empty() {
+                record_snd.send(batch).context("Failed to send record")?;
             }
             drop(record_snd);
             Ok(())
-        });
+        }));
 
-        // Create threads to receive records from record_recv, align them, and send them to aligned_snd
-        for _ in 0..num_threads {
+        // Create threads to receive batches from record_recv, align them, and send them to aligned_snd
+        for _ in 0..args.threads {
             let record_receiver = record_recv.clone();
             let result_sender = aligned_snd.clone();
+            // Cloning the aligner only copies the handle to the index, so every worker shares
+            // the same index in memory rather than holding its own copy
             let mut aligner = aligner.clone();
+            let profile = &profile;
+            let summary = &summary;
+            let primers = &primers;
+            let renames = &renames;
+            let query_dump = query_dump.as_ref();
 
-            s.spawn(move || -> Result<()> {
-                while let Ok(record) = record_receiver.recv() {
-                    let aligned_record = align_record(&record, &reference, &aligner)
-                        .context("Failed to align record")?;
-                    result_sender.send(aligned_record).context("Failed to send aligned record")?;
+            handles.push(s.spawn(move || -> Result<()> {
+                while let Ok(batch) = record_receiver.recv() {
+                    for (index, record) in batch {
+                        let record = match primers.as_ref().and_then(|primers| primers.trim(record.sequence().as_ref())) {
+                            Some(range) => {
+                                summary::Summary::increment(&summary.primer_trimmed);
+                                let sequence = record.sequence().as_ref()[range].to_vec();
+                                Record::new(record.definition().clone(), Sequence::from(sequence))
+                            }
+                            None => record,
+                        };
+                        let record = if args.respect_read_mask {
+                            mask_lowercase(record)
+                        } else {
+                            record
+                        };
+                        // This is exactly the sequence align_record hands to minimap2
+                        if let Some(dump) = query_dump {
+                            let mut dump = dump.lock().map_err(|_| anyhow!("Query dump lock poisoned"))?;
+                            dump.write_record(&record).context("Failed to write query dump")?;
+                        }
+                        // Renamed after the dump, which keeps the input names minimap2 saw
+                        let record = match renames.as_ref().and_then(|renames| renames.get(record.name())) {
+                            Some(new_name) => {
+                                let definition = Definition::new(new_name.clone(), record.description().map(<[u8]>::to_vec));
+                                Record::new(definition, record.sequence().clone())
+                            }
+                            None => record,
+                        };
+                        let mut aligned_reads = profile.time(profile::Stage::Align, || align_record(&record, reference, &aligner, args))
+                            .with_context(|| format!("Failed to align record {}", String::from_utf8_lossy(record.name())))?;
+                        if aligned_reads.is_empty() {
+                            summary::Summary::increment(&summary.unmapped);
+                            aligned_reads.extend(args.emits_unmapped().then(|| unmapped_read(&record, reference, args)));
+                        } else {
+                            let unanchored = aligned_reads.iter().any(is_unanchored);
+                            if unanchored {
+                                summary::Summary::increment(&summary.unanchored);
+                                if args.drop_unanchored {
+                                    aligned_reads.retain(|aligned_read| !is_unanchored(aligned_read));
+                                }
+                            }
+                            aligned_reads.retain(|aligned_read| !below_min_coverage(aligned_read, reference, args.min_ref_coverage));
+                            if aligned_reads.is_empty() {
+                                // Reads dropped as unanchored are already counted
+                                if !unanchored {
+                                    summary::Summary::increment(&summary.low_coverage);
+                                }
+                            } else {
+                                summary::Summary::increment(&summary.mapped);
+                            }
+                        }
+                        // Unmapped reads are still sent so the writer knows their place in the input order
+                        result_sender.send((index, aligned_reads)).context("Failed to send aligned record")?;
+                    }
                 }
                 // stops a double free seg fault, see https://github.com/jguhlin/minimap2-rs/issues/71
                 aligner.idx = None;
                 Ok(())
-            });
+            }));
         }
+        // Only the workers may hold the receiving ends, so a failed stage unblocks the one feeding it
+        drop(record_recv);
         drop(aligned_snd);
 
         // Final thread to receive aligned records from aligned_recv and write them
-        s.spawn(move || -> Result<()>{
-            while let Ok(aligned_record) = aligned_recv.recv() {
-                output_writer.write_record(&aligned_record)
-                    .context("Failed to write aligned record")?;
+        let profile = &profile;
+        handles.push(s.spawn(move || -> Result<()>{
+            let mut output = output::Output::new(args, reference, profile, output_writer)?;
+            let mut reorder = output::Reorder::new(args.keep_order);
+            // Unmapped rows held back to be written after all of the mapped reads
+            let mut unmapped = Vec::new();
+            // Mapped reads held back until the best --top-n of them are known
+            let mut candidates = Vec::new();
+
+            while let Ok((index, aligned_reads)) = aligned_recv.recv() {
+                for aligned_read in reorder.push(index, aligned_reads).into_iter().flatten() {
+                    if aligned_read.mapped && args.top_n.is_some() {
+                        candidates.push(aligned_read);
+                    } else if aligned_read.mapped || !args.unmapped_last {
+                        output.write(aligned_read)?;
+                    } else {
+                        unmapped.push(aligned_read);
+                    }
+                }
             }
-            Ok(())
-        });
+            if let Some(top_n) = args.top_n {
+                for aligned_read in best_reads(candidates, top_n) {
+                    output.write(aligned_read)?;
+                }
+            }
+            for aligned_read in unmapped {
+                output.write(aligned_read)?;
+            }
+            output.finish()
+        }));
 
-        Ok(())
-    }).context("Thread error")?;
+        // A failing thread makes the threads feeding it fail to send, so the last error is the root cause
+        let results: Vec<Result<()>> = handles.into_iter()
+            .map(|handle| handle.join().unwrap_or_else(|_| Err(anyhow!("Thread panicked"))))
+            .collect();
+        results.into_iter().rev().find(|result| result.is_err()).unwrap_or(Ok(()))
+    })?;
 
-    Ok(())
+    if let Some(dump) = query_dump {
+        let mut dump = dump.into_inner().map_err(|_| anyhow!("Query dump lock poisoned"))?;
+        dump.get_mut().flush().context("Failed to write query dump")?;
+    }
+
+    summary.report();
+    if args.profile {
+        profile.report(start_time.elapsed(), args.threads);
+    }
+    if args.benchmark_mode {
+        profile.report_throughput(start_time.elapsed());
+    }
+
+    Ok(summary.mapped())
+}
+
+/// The `n` reads with the highest identity, in the order they arrived
+fn best_reads(reads: Vec<AlignedRead>, n: usize) -> Vec<AlignedRead> {
+    let mut ranked: Vec<(usize, AlignedRead)> = reads.into_iter().enumerate().collect();
+    ranked.sort_by(|(_, a), (_, b)| b.query.identity().total_cmp(&a.query.identity()));
+    ranked.truncate(n);
+    ranked.sort_by_key(|(arrival, _)| *arrival);
+    ranked.into_iter().map(|(_, read)| read).collect()
 }
 
-fn fastalign() -> Result<()> {
-    let args = cli::Cli::parse();
+/// Channel between pipeline stages, bounded so a slow stage applies backpressure when a capacity is set
+fn channel<T>(capacity: Option<usize>) -> (Sender<T>, Receiver<T>) {
+    match capacity {
+        Some(capacity) => bounded(capacity),
+        None => unbounded(),
+    }
+}
+
+/// Print the resolved minimap2 options the aligner will run with
+fn explain_aligner(aligner: &Aligner, sam_hit_only: bool) {
+    let idxopt = &aligner.idxopt;
+    let mapopt = &aligner.mapopt;
+    logging::report!("Preset: asm20");
+    logging::report!("Aligner flags: cigar={} sam_hit_only={}", aligner.mapopt.flag & MM_F_CIGAR != 0, sam_hit_only);
+    logging::report!("Index options: k={} w={} flag={:#x} bucket_bits={}", idxopt.k, idxopt.w, idxopt.flag, idxopt.bucket_bits);
+    logging::report!(
+        "Scoring: a={} b={} q={} e={} q2={} e2={} sc_ambi={}",
+        mapopt.a, mapopt.b, mapopt.q, mapopt.e, mapopt.q2, mapopt.e2, mapopt.sc_ambi
+    );
+    logging::report!(
+        "Mapping options: flag={:#x} bw={} bw_long={} zdrop={} zdrop_inv={} min_cnt={} min_chain_score={} best_n={}",
+        mapopt.flag, mapopt.bw, mapopt.bw_long, mapopt.zdrop, mapopt.zdrop_inv, mapopt.min_cnt, mapopt.min_chain_score, mapopt.best_n
+    );
+    logging::report!(
+        "Seeding: mid_occ={} min_mid_occ={} max_mid_occ={} max_occ={} mid_occ_frac={}",
+        mapopt.mid_occ, mapopt.min_mid_occ, mapopt.max_mid_occ, mapopt.max_occ, mapopt.mid_occ_frac
+    );
+}
 
-    let aligner = Aligner {
+/// Aligner with the preset and options shared by every subcommand, before it is indexed
+fn configure_aligner(args: &cli::AlignerArgs) -> Result<Aligner> {
+    let mut aligner = Aligner {
         mapopt: MapOpt {
             sc_ambi: 0,
             ..Aligner::builder().asm20().mapopt
         },
         ..Aligner::builder().asm20()
+    };
+    if args.hpc {
+        aligner.idxopt.flag |= MM_I_HPC;
+    }
+    if let Some(min_occ_floor) = args.min_occ_floor {
+        aligner.mapopt.min_mid_occ = min_occ_floor;
+    }
+    if let Some(max_occ) = args.max_occ {
+        aligner.mapopt.max_occ = max_occ;
+    }
+    if let Some(gap_open2) = args.gap_open2 {
+        aligner.mapopt.q2 = gap_open2;
+    }
+    if let Some(gap_extend2) = args.gap_extend2 {
+        aligner.mapopt.e2 = gap_extend2;
     }
+    if let Some(mm2_opts) = &args.mm2_opts {
+        mm2_opts::apply(&mut aligner, mm2_opts).context("Invalid --mm2-opts")?;
+    }
+    Ok(aligner)
+}
+
+/// Align a single sequence and print what minimap2 made of it, returning the number of reads that mapped
+fn query(args: &cli::QueryArgs) -> Result<usize> {
+    let reference = reference::Reference::load(std::slice::from_ref(&args.reference))?;
+    if reference.total_len() == 0 {
+        return Err(anyhow!("Reference has no sequence to align to: {}", args.reference));
+    }
+    let aligner = configure_aligner(&args.aligner)?
         .with_cigar()
-        .with_sam_hit_only()
         .with_index(&args.reference, None)
         .map_err(|e| anyhow!(e))
         .context("Failed to build aligner")?;
 
-    let mut reference = String::new();
-    let ref_file = File::open(&args.reference).context("Failed to open reference file")?;
-    let mut ref_reader = fasta::Reader::new(BufReader::new(ref_file));
-    for record in ref_reader.records() {
-        let record = record.context("Failed to read reference FASTA record")?;
-        reference.push_str(std::str::from_utf8(record.sequence().as_ref()).context("Invalid UTF-8 reference sequence")?);
-    }
+    let sequence = args.seq.as_bytes();
+    let alignment = aligner.map(sequence, false, false, None, None)
+        .map_err(|e| anyhow!(e))
+        .context("Failed to align sequence")?;
 
-    let num_threads = args.threads;
-    process_fasta(&args.input, &args.output, &reference, &aligner, num_threads)?;
+    let Some(aln) = select_alignment(&alignment, sequence, false)? else {
+        println!("No alignment found");
+        return Ok(0);
+    };
+    let contig = aln.target_name.as_ref()
+        .and_then(|target| reference.get(target))
+        .context("Unknown reference target for alignment")?;
+    let cigar_string = aln.alignment.as_ref().and_then(|a| a.cigar_str.as_ref())
+        .context("No CIGAR string found for alignment")?;
+    let (aligned_seq, _) = align_sequence(&aligned_query(sequence, aln), &contig.sequence, contig.sequence.len(), cigar_string, aln.target_start, false)?;
 
+    println!("target\t{}", contig.name);
+    println!("start\t{}", aln.target_start);
+    println!("end\t{}", aln.target_end);
+    println!("strand\t{}", strand_symbol(&aln.strand));
+    println!("mapq\t{}", aln.mapq);
+    println!("cigar\t{}", cigar_string);
+    println!("aligned\t{}", String::from_utf8_lossy(&aligned_seq));
+    Ok(1)
+}
+
+/// Aligning the reference to itself gives a trivial diagonal MSA, which is almost always a mistake
+fn check_self_align(args: &cli::RunArgs) -> Result<()> {
+    // Paths that can't be resolved are left for the readers to report
+    let Ok(input) = std::fs::canonicalize(&args.input) else {
+        return Ok(());
+    };
+    let same_file = args.reference.iter()
+        .any(|reference| std::fs::canonicalize(reference).is_ok_and(|reference| reference == input));
+    if same_file {
+        if args.no_self_align {
+            return Err(anyhow!("Input {} is also used as a reference", args.input));
+        }
+        log::warn!("Input {} is also used as a reference, reads will align to themselves", args.input);
+    }
     Ok(())
 }
 
+/// Align the input FASTA against the reference, returning the number of reads that mapped
+fn run(args: &cli::RunArgs) -> Result<usize> {
+    check_self_align(args)?;
+    let aligner = configure_aligner(&args.aligner)?;
+
+    let mut reference = match &args.region {
+        Some(_) if args.reference.len() > 1 => {
+            return Err(anyhow!("--region needs a single reference file, got {}", args.reference.len()));
+        }
+        Some(region) => reference::Reference::load_region(&args.reference[0], region)?,
+        None => reference::Reference::load(&args.reference)?,
+    };
+    if let Some(contigs) = &args.ref_contigs {
+        reference.retain(&reference::parse_name_list(contigs)?)?;
+    }
+    if let Some(min_run) = args.split_on_n {
+        reference = reference.split_on_n(min_run)?;
+    }
+    if reference.total_len() == 0 {
+        return Err(anyhow!("Reference has no sequence to align to: {}", args.reference.join(", ")));
+    }
+    if args.no_pad && matches!(args.out_format, cli::OutFormat::Matrix | cli::OutFormat::Stockholm | cli::OutFormat::Html | cli::OutFormat::Pretty) {
+        return Err(anyhow!("--no-pad rows differ in length, so can't be written as a matrix, Stockholm, HTML or pretty alignment"));
+    }
+    // The Stockholm consensus line tallies nucleotides, so has no meaning for amino acid rows
+    if args.translate && args.out_format == cli::OutFormat::Stockholm {
+        return Err(anyhow!("--translate can't be written as a Stockholm alignment"));
+    }
+    if args.pairwise_identity.is_some() && args.out_format.is_coordinate_only() {
+        return Err(anyhow!("--pairwise-identity needs aligned rows, which coordinate-only formats don't build"));
+    }
+    if args.pileup.is_some() && args.out_format.is_coordinate_only() {
+        return Err(anyhow!("--pileup needs aligned rows, which coordinate-only formats don't build"));
+    }
+    if args.consensus.is_some() && args.out_format.is_coordinate_only() {
+        return Err(anyhow!("--consensus needs aligned rows, which coordinate-only formats don't build"));
+    }
+    if args.insertions_out.is_some() && args.out_format.is_coordinate_only() {
+        return Err(anyhow!("--insertions-out needs aligned rows, which coordinate-only formats don't build"));
+    }
+    if args.msa_identity && args.out_format.is_coordinate_only() {
+        return Err(anyhow!("--msa-identity needs aligned rows, which coordinate-only formats don't build"));
+    }
+    if args.msa_identity && reference.contigs.len() > 1 {
+        return Err(anyhow!("--msa-identity needs a single reference contig"));
+    }
+    if args.split_by_strand.is_some() && args.out_format != cli::OutFormat::Fasta {
+        return Err(anyhow!("--split-by-strand is only supported with FASTA output"));
+    }
+    if args.emit_revcomp && args.out_format != cli::OutFormat::Fasta {
+        return Err(anyhow!("--emit-revcomp is only supported with FASTA output"));
+    }
+    if args.append && !matches!(args.out_format, cli::OutFormat::Fasta | cli::OutFormat::Bed | cli::OutFormat::Paf) {
+        return Err(anyhow!("--append is only supported with FASTA, BED or PAF output"));
+    }
+    if args.chunk_output.is_some() && args.out_format != cli::OutFormat::Fasta {
+        return Err(anyhow!("--chunk-output is only supported with FASTA output"));
+    }
+    if let Some(max_ref_len) = args.max_ref_len {
+        if reference.total_len() > max_ref_len {
+            return Err(anyhow!(
+                "Reference length {} exceeds --max-ref-len {}, use --ref-contigs to align against a subset of the reference",
+                reference.total_len(), max_ref_len
+            ));
+        }
+    }
+
+    log::info!("Loaded {} reference contigs, {} bases", reference.contigs.len(), reference.total_len());
+
+    // Multiple or modified references are written to a single temporary FASTA so they share one index
+    let modified_reference = args.ref_contigs.is_some() || args.split_on_n.is_some() || args.region.is_some() || reference.stripped_whitespace;
+    // Created with a fresh name and removed when dropped, so an early return can't leave it behind
+    let combined_reference = if args.reference.len() > 1 || modified_reference {
+        let mut file = tempfile::Builder::new()
+            .prefix("fastalign-")
+            .suffix("-reference.fa")
+            .tempfile()
+            .context("Failed to create temporary reference file")?;
+        reference.write_fasta(file.as_file_mut())?;
+        Some(file)
+    } else {
+        None
+    };
+    let index_path = match &combined_reference {
+        Some(file) => file.path().to_str().context("Invalid temporary reference path")?.to_string(),
+        None => args.reference[0].clone(),
+    };
+
+    // Coordinate-only output doesn't use the CIGAR, so skip computing it
+    let aligner = if args.needs_cigar() { aligner.with_cigar() } else { aligner };
+    // Hit-only filtering is left off when unmapped reads are kept as placeholder rows
+    let aligner = if args.emits_unmapped() { aligner } else { aligner.with_sam_hit_only() };
+    let aligner = aligner
+        .with_index(&index_path, None)
+        .map_err(|e| anyhow!(e))
+        .context("Failed to build aligner");
+
+    if let Some(file) = combined_reference {
+        file.close().context("Failed to remove temporary reference file")?;
+    }
+    let aligner = aligner?;
+
+    if args.explain {
+        explain_aligner(&aligner, !args.emits_unmapped());
+    }
+
+    let mapped = process_fasta(args, &reference, &aligner)?;
+    if let Some(bundle_path) = &args.bundle {
+        bundle::write_bundle(bundle_path, &args.output_files())?;
+    }
+    Ok(mapped)
+}
+
+/// Run fastalign, returning the number of reads that mapped
+fn fastalign() -> Result<usize> {
+    let cli = cli::parse();
+    logging::init(cli.quiet, cli.verbose);
+    match cli.command {
+        cli::Command::Run(args) => run(&args),
+        cli::Command::Query(args) => query(&args),
+    }
+}
+
 fn main() {
-    if let Err(e) = fastalign() {
-        eprintln!("Error: {}", e);
-        std::process::exit(1);
+    match fastalign() {
+        Ok(0) => {
+            log::warn!("No reads mapped to the reference");
+            std::process::exit(EXIT_NO_READS_MAPPED);
+        }
+        Ok(_) => {}
+        Err(e) => {
+            // The alternate form prints the whole context chain, not just the outermost message
+            log::error!("{:#}", e);
+            std::process::exit(1);
+        }
+    }
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+
+    #[test]
+    fn parses_every_operation() {
+        let cigar = parse_cigar("5S10M2I3D4N1H2P6=7X").unwrap();
+        assert!(matches!(
+            cigar.as_slice(),
+            [
+                CigarOperation::SoftClip(5),
+                CigarOperation::Match(10),
+                CigarOperation::Insertion(2),
+                CigarOperation::Deletion(3),
+                CigarOperation::Skipped(4),
+                CigarOperation::HardClip(1),
+                CigarOperation::Pad(2),
+                CigarOperation::Equal(6),
+                CigarOperation::Diff(7),
+            ]
+        ));
+    }
+
+    #[test]
+    fn splits_operations_on_equals() {
+        let cigar = parse_cigar("12=1X30=").unwrap();
+        assert!(matches!(cigar.as_slice(), [CigarOperation::Equal(12), CigarOperation::Diff(1), CigarOperation::Equal(30)]));
+    }
+
+    #[test]
+    fn empty_cigar_has_no_operations() {
+        assert!(parse_cigar("").unwrap().is_empty());
+    }
+
+    #[test]
+    fn rejects_trailing_digits() {
+        assert!(parse_cigar("10M5").is_err());
+        assert!(parse_cigar("10").is_err());
+    }
+
+    #[test]
+    fn rejects_missing_counts() {
+        assert!(parse_cigar("M").is_err());
+        assert!(parse_cigar("10MM").is_err());
+        assert!("".parse::<CigarOperation>().is_err());
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn rejects_malformed_operations() {
+        assert!(parse_cigar("10Q").is_err());
+        assert!(parse_cigar("-5M").is_err());
+        assert!(parse_cigar("5 M").is_err());
+        assert!(parse_cigar("99999999999999999999999M").is_err());
+    }
+
+    #[test]
+    fn rejects_multibyte_operations() {
+        assert!(parse_cigar("10é").is_err());
+        assert!(parse_cigar("é").is_err());
+        assert!(parse_cigar("3M1€2D").is_err());
+        assert!("10é".parse::<CigarOperation>().is_err());
+    }
+
+    proptest! {
+        #[test]
+        fn parse_cigar_never_panics(cigar in "\\PC*") {
+            let _ = parse_cigar(&cigar);
+        }
+
+        #[test]
+        fn parse_cigar_never_panics_on_bytes(bytes in prop::collection::vec(any::<u8>(), 0..64)) {
+            let _ = parse_cigar(&String::from_utf8_lossy(&bytes));
+        }
+
+        #[test]
+        fn parses_generated_cigars(operations in prop::collection::vec((1usize..10_000, "[MIDNSHP=X]"), 0..20)) {
+            let cigar: String = operations.iter().map(|(count, operation)| format!("{}{}", count, operation)).collect();
+            prop_assert_eq!(parse_cigar(&cigar).unwrap().len(), operations.len());
+        }
+    }
+
+    #[test]
+    fn left_aligns_homopolymer_deletion() {
+        let reference = b"ACAAAT";
+        let mut row = b"ACAA-T".to_vec();
+        normalize_indels(&mut row, reference, 0, reference.len());
+        assert_eq!(row, b"AC-AAT");
+    }
+
+    #[test]
+    fn fills_only_gaps_up_to_the_threshold() {
+        let reference = b"ACGTACGTACGTAC";
+        let mut row = b"AC-TAC-----TAC".to_vec();
+        fill_short_gaps(&mut row, reference, 0, reference.len(), 2);
+        assert_eq!(row, b"ACGTAC-----TAC");
+    }
+}
@@ -0,0 +1,38 @@
+use std::io::Write;
+use anyhow::{Result, Context};
+use crate::rows::RowStore;
+use crate::sidecar;
+
+/// Columns read from every row at a time, so spilled rows are read in blocks rather than once per column
+const COLUMN_BLOCK: usize = 4096;
+
+/// Write the alignment transposed as a TSV, one line per column and one field per read
+/// Rows shorter than the longest row are padded with gaps
+pub fn write_matrix<W: Write>(writer: &mut W, rows: &mut RowStore, coordinate_base: u8) -> Result<()> {
+    write!(writer, "position")?;
+    for name in rows.names() {
+        write!(writer, "\t{}", String::from_utf8_lossy(name))?;
+    }
+    writeln!(writer)?;
+
+    let columns = rows.max_len();
+    let mut line = Vec::with_capacity(rows.count() * 2);
+    for block_start in (0..columns).step_by(COLUMN_BLOCK) {
+        let block = block_start..(block_start + COLUMN_BLOCK).min(columns);
+        let pieces = (0..rows.count())
+            .map(|index| rows.read(index, block.clone()).map(|piece| piece.into_owned()))
+            .collect::<Result<Vec<_>>>()?;
+        for column in block {
+            line.clear();
+            for piece in &pieces {
+                line.push(b'\t');
+                line.push(piece.get(column - block_start).copied().unwrap_or(b'-'));
+            }
+            write!(writer, "{}", sidecar::position(column, coordinate_base))?;
+            writer.write_all(&line)?;
+            writeln!(writer)?;
+        }
+    }
+    writer.flush().context("Failed to write alignment matrix")?;
+    Ok(())
+}
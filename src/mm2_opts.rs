@@ -0,0 +1,62 @@
+use anyhow::{Result, Context, anyhow};
+use minimap2::Aligner;
+
+/// Parse `value` into the option field, whatever its integer or float type
+macro_rules! set_option {
+    ($field:expr, $key:expr, $value:expr) => {
+        $field = $value.parse()
+            .with_context(|| format!("Invalid value for minimap2 option {}: {}", $key, $value))?
+    };
+}
+
+/// Apply comma separated `key=value` minimap2 options, e.g. `k=19,w=10,bw=1000`
+/// Keys are the field names of minimap2's mm_idxopt_t and mm_mapopt_t, anything else is an error
+pub fn apply(aligner: &mut Aligner, spec: &str) -> Result<()> {
+    for option in spec.split(',').map(str::trim).filter(|option| !option.is_empty()) {
+        let (key, value) = option.split_once('=')
+            .ok_or_else(|| anyhow!("minimap2 options must be key=value, got: {}", option))?;
+        let (key, value) = (key.trim(), value.trim());
+        let idxopt = &mut aligner.idxopt;
+        let mapopt = &mut aligner.mapopt;
+        match key {
+            // Index options
+            "k" => set_option!(idxopt.k, key, value),
+            "w" => set_option!(idxopt.w, key, value),
+            "bucket_bits" => set_option!(idxopt.bucket_bits, key, value),
+            "batch_size" => set_option!(idxopt.batch_size, key, value),
+            // Chaining
+            "bw" => set_option!(mapopt.bw, key, value),
+            "bw_long" => set_option!(mapopt.bw_long, key, value),
+            "max_gap" => set_option!(mapopt.max_gap, key, value),
+            "max_gap_ref" => set_option!(mapopt.max_gap_ref, key, value),
+            "max_chain_skip" => set_option!(mapopt.max_chain_skip, key, value),
+            "max_chain_iter" => set_option!(mapopt.max_chain_iter, key, value),
+            "min_cnt" => set_option!(mapopt.min_cnt, key, value),
+            "min_chain_score" => set_option!(mapopt.min_chain_score, key, value),
+            "mask_level" => set_option!(mapopt.mask_level, key, value),
+            "pri_ratio" => set_option!(mapopt.pri_ratio, key, value),
+            "best_n" => set_option!(mapopt.best_n, key, value),
+            // Scoring
+            "a" => set_option!(mapopt.a, key, value),
+            "b" => set_option!(mapopt.b, key, value),
+            "q" => set_option!(mapopt.q, key, value),
+            "e" => set_option!(mapopt.e, key, value),
+            "q2" => set_option!(mapopt.q2, key, value),
+            "e2" => set_option!(mapopt.e2, key, value),
+            "sc_ambi" => set_option!(mapopt.sc_ambi, key, value),
+            "zdrop" => set_option!(mapopt.zdrop, key, value),
+            "zdrop_inv" => set_option!(mapopt.zdrop_inv, key, value),
+            "end_bonus" => set_option!(mapopt.end_bonus, key, value),
+            "min_dp_max" => set_option!(mapopt.min_dp_max, key, value),
+            "min_ksw_len" => set_option!(mapopt.min_ksw_len, key, value),
+            // Seed occurrence
+            "mid_occ" => set_option!(mapopt.mid_occ, key, value),
+            "min_mid_occ" => set_option!(mapopt.min_mid_occ, key, value),
+            "max_occ" => set_option!(mapopt.max_occ, key, value),
+            "max_max_occ" => set_option!(mapopt.max_max_occ, key, value),
+            "occ_dist" => set_option!(mapopt.occ_dist, key, value),
+            _ => return Err(anyhow!("Unknown minimap2 option: {}", key)),
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,527 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use anyhow::{Result, Context, anyhow};
+use noodles::fasta;
+use noodles::fasta::record::{Definition, Sequence};
+use crate::{alphabet, cli, consensus, html, logging, matrix, pairwise, pretty, sidecar, stats, stockholm, writer, AlignedRead};
+use crate::pileup::Pileup;
+use crate::profile::{Profile, Stage};
+use crate::reference::Reference;
+use crate::rows::RowStore;
+use crate::writer::OutputWriter;
+
+/// Everything written from the aligned reads, the main output along with any sidecar files
+pub struct Output<'a> {
+    args: &'a cli::RunArgs,
+    reference: &'a Reference,
+    profile: &'a Profile,
+    writer: fasta::Writer<OutputWriter>,
+    // Rows of reverse strand reads with --split-by-strand
+    reverse_writer: Option<fasta::Writer<OutputWriter>>,
+    insertions_writer: Option<BufWriter<File>>,
+    stats_writer: Option<BufWriter<File>>,
+    stats_json_writer: Option<BufWriter<File>>,
+    clips_writer: Option<BufWriter<File>>,
+    bed_writer: Option<BufWriter<File>>,
+    gff_writer: Option<BufWriter<File>>,
+    chimera_writer: Option<BufWriter<File>>,
+    consensus_groups: HashMap<consensus::GroupKey, consensus::ColumnCounts>,
+    group_field: Option<cli::NameField>,
+    pileup: Option<Pileup>,
+    // Every row's columns, for --msa-identity
+    msa_counts: Option<consensus::ColumnCounts>,
+    // Formats that need every row before they can be written
+    buffered: RowStore,
+    // Every row, kept for --pairwise-identity
+    pairwise_rows: Option<RowStore>,
+    collapsed: Collapsed,
+    written: u64,
+    chunk: usize,
+    // Records written to the current chunk
+    chunk_records: u64,
+}
+
+impl<'a> Output<'a> {
+    pub fn new(args: &'a cli::RunArgs, reference: &'a Reference, profile: &'a Profile, writer: OutputWriter) -> Result<Self> {
+        let mut insertions_writer = args.insertions_out.as_deref().map(sidecar::create_sidecar).transpose()?;
+        if let Some(writer) = insertions_writer.as_mut() {
+            writeln!(writer, "name\treference\tposition\tlength\tbases")?;
+        }
+        let mut stats_writer = args.tsv.as_deref().map(sidecar::create_sidecar).transpose()?;
+        if let Some(writer) = stats_writer.as_mut() {
+            writeln!(writer, "{}", stats::TSV_HEADER)?;
+        }
+        let mut clips_writer = args.clip_report.as_deref().map(sidecar::create_sidecar).transpose()?;
+        if let Some(writer) = clips_writer.as_mut() {
+            writeln!(writer, "name\tfive_prime_length\tfive_prime\tthree_prime_length\tthree_prime")?;
+        }
+
+        let bed_writer = args.bed.as_deref().map(sidecar::create_sidecar).transpose()?;
+        let mut gff_writer = args.gff.as_deref().map(sidecar::create_sidecar).transpose()?;
+        if let Some(writer) = gff_writer.as_mut() {
+            writeln!(writer, "##gff-version 3")?;
+            // --split-on-n pieces of the same contig share one region, spanning them all
+            let mut regions: Vec<(&str, usize, usize)> = Vec::new();
+            for contig in &reference.contigs {
+                let end = contig.offset + contig.sequence.len();
+                match regions.last_mut() {
+                    Some((name, _, region_end)) if *name == contig.source_name => *region_end = end,
+                    _ => regions.push((contig.source_name.as_str(), contig.offset, end)),
+                }
+            }
+            for (name, start, end) in regions {
+                writeln!(writer, "##sequence-region {} {} {}", gff_escape(name), start + 1, end)?;
+            }
+        }
+        let mut chimera_writer = args.detect_chimera.as_deref().map(sidecar::create_sidecar).transpose()?;
+        if let Some(writer) = chimera_writer.as_mut() {
+            writeln!(writer, "name\tsegment\ttarget\tstart\tend\tstrand\tquery_start\tquery_end")?;
+        }
+
+        Ok(Self {
+            args,
+            reference,
+            profile,
+            writer: fasta::Writer::new(writer),
+            reverse_writer: args.split_by_strand.as_deref()
+                .map(|path| writer::open_output(path, args.bgzf, args.append, args.threads_io).map(fasta::Writer::new))
+                .transpose()?,
+            insertions_writer,
+            stats_writer,
+            stats_json_writer: args.stats_json.as_deref().map(sidecar::create_sidecar).transpose()?,
+            clips_writer,
+            bed_writer,
+            gff_writer,
+            chimera_writer,
+            consensus_groups: HashMap::new(),
+            group_field: args.group_field(),
+            pileup: args.pileup.as_ref().map(|_| Pileup::default()),
+            msa_counts: args.msa_identity.then(consensus::ColumnCounts::default),
+            buffered: RowStore::new(args.spill_dir.as_deref(), "rows", args.max_memory)?,
+            pairwise_rows: args.pairwise_identity.as_ref().map(|_| RowStore::new(args.spill_dir.as_deref(), "pairwise", args.max_memory)).transpose()?,
+            collapsed: Collapsed::default(),
+            written: 0,
+            chunk: 1,
+            chunk_records: 0,
+        })
+    }
+
+    /// Write a single aligned read to the output and sidecars
+    pub fn write(&mut self, mut aligned_read: AlignedRead) -> Result<()> {
+        let args = self.args;
+        // Tallied before any --reference-offset shift, while row columns are still reference positions
+        // Unmapped placeholder rows are all gaps, so they're left out
+        if aligned_read.mapped {
+            let row = aligned_read.record.sequence().as_ref();
+            if let Some(pileup) = self.pileup.as_mut() {
+                pileup.add(&aligned_read.target, row, aligned_read.start, aligned_read.end);
+            }
+            if args.consensus.is_some() {
+                let key = consensus::group_key(aligned_read.record.name(), &aligned_read.target, self.group_field.as_ref());
+                self.consensus_groups.entry(key).or_default().add_span(row, aligned_read.start..aligned_read.end);
+            }
+            if let Some(counts) = self.msa_counts.as_mut() {
+                counts.add_span(row, aligned_read.start..aligned_read.end);
+            }
+        }
+        if args.reference_offset != 0 && !args.out_format.is_coordinate_only() {
+            let padding = if args.distinguish_coverage { b'.' } else { b'-' };
+            aligned_read.record = shift_row(&aligned_read.record, args.reference_offset, padding);
+        }
+        if args.validate_output && !args.out_format.is_coordinate_only() {
+            self.validate(&aligned_read)?;
+        }
+        let aligned_record = &aligned_read.record;
+        let revcomp_record = (args.emit_revcomp && aligned_read.mapped && !args.out_format.is_coordinate_only())
+            .then(|| reverse_complement_record(aligned_record));
+        // Collapsed rows are only written once every read has been counted
+        let collapse = args.collapse_identical && !args.out_format.is_coordinate_only();
+        if args.out_format == cli::OutFormat::Fasta && !collapse {
+            for record in std::iter::once(aligned_record).chain(revcomp_record.as_ref()) {
+                // Chunks are counted in records, a read with --emit-revcomp writes two
+                if args.chunk_output.is_some_and(|size| self.chunk_records == size) {
+                    self.next_chunk()?;
+                }
+                let writer = match self.reverse_writer.as_mut() {
+                    Some(reverse_writer) if aligned_read.strand == '-' => reverse_writer,
+                    _ => &mut self.writer,
+                };
+                self.profile.time(Stage::Write, || writer.write_record(record)).context("Failed to write aligned record")?;
+                self.chunk_records += 1;
+            }
+        }
+        // Coordinates are placed back on the original contig when aligning to a --region or --split-on-n pieces
+        let (target, offset) = self.reference.locate(&aligned_read.target);
+        if args.out_format == cli::OutFormat::Bed && aligned_read.mapped {
+            let writer = self.writer.get_mut();
+            self.profile.time(Stage::Write, || write_bed(writer, &aligned_read, target, offset)).context("Failed to write BED record")?;
+        }
+        if args.out_format == cli::OutFormat::Paf && aligned_read.mapped {
+            let target_len = self.reference.get(&aligned_read.target).map_or(0, |contig| contig.source_len);
+            let writer = self.writer.get_mut();
+            self.profile.time(Stage::Write, || write_paf(writer, &aligned_read, target, target_len, offset)).context("Failed to write PAF record")?;
+        }
+        if let Some(writer) = self.bed_writer.as_mut().filter(|_| aligned_read.mapped) {
+            write_bed(writer, &aligned_read, target, offset).context("Failed to write BED record")?;
+        }
+        // Reads without a reference anchor have no span to describe
+        if let Some(writer) = self.gff_writer.as_mut().filter(|_| aligned_read.mapped && aligned_read.end > aligned_read.start) {
+            write_gff(writer, &aligned_read, target, self.written + 1, offset).context("Failed to write GFF feature")?;
+        }
+        if let Some(writer) = self.insertions_writer.as_mut() {
+            for insertion in &aligned_read.insertions {
+                writeln!(
+                    writer, "{}\t{}\t{}\t{}\t{}",
+                    String::from_utf8_lossy(aligned_record.name()),
+                    target,
+                    sidecar::position(insertion.ref_pos + offset, args.coordinate_base),
+                    insertion.bases.len(),
+                    String::from_utf8_lossy(&insertion.bases),
+                )?;
+            }
+        }
+        if let Some(stats) = &aligned_read.stats {
+            let row = stats::StatsRow::new(&aligned_read, stats, args.coordinate_base, target, offset);
+            if let Some(writer) = self.stats_writer.as_mut() {
+                row.write_tsv(writer)?;
+            }
+            if let Some(writer) = self.stats_json_writer.as_mut() {
+                row.write_json(writer)?;
+            }
+        }
+        if let Some(writer) = self.chimera_writer.as_mut() {
+            for (i, segment) in aligned_read.segments.iter().enumerate() {
+                let (segment_target, segment_offset) = self.reference.locate(&segment.target);
+                writeln!(
+                    writer, "{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}",
+                    String::from_utf8_lossy(aligned_record.name()),
+                    i + 1,
+                    segment_target,
+                    sidecar::position(segment.start + segment_offset, args.coordinate_base),
+                    segment.end + segment_offset,
+                    segment.strand,
+                    segment.query_start,
+                    segment.query_end,
+                )?;
+            }
+        }
+        if let (Some(writer), Some(clips)) = (self.clips_writer.as_mut(), &aligned_read.clips) {
+            writeln!(
+                writer, "{}\t{}\t{}\t{}\t{}",
+                String::from_utf8_lossy(aligned_record.name()),
+                clips.five_prime.len(),
+                String::from_utf8_lossy(&clips.five_prime),
+                clips.three_prime.len(),
+                String::from_utf8_lossy(&clips.three_prime),
+            )?;
+        }
+        self.written += 1;
+        if args.flush_interval.is_some_and(|interval| self.written % interval == 0) {
+            self.writer.get_mut().flush().context("Failed to flush output file")?;
+        }
+        if args.checkpoint_every.is_some_and(|interval| self.written % interval == 0) {
+            self.checkpoint()?;
+        }
+        if let Some(rows) = self.pairwise_rows.as_mut() {
+            rows.push(aligned_read.record.clone())?;
+        }
+        if collapse {
+            self.collapsed.add(aligned_read.record);
+            if let Some(revcomp_record) = revcomp_record {
+                self.collapsed.add(revcomp_record);
+            }
+        } else if matches!(args.out_format, cli::OutFormat::Matrix | cli::OutFormat::Stockholm | cli::OutFormat::Html | cli::OutFormat::Pretty) {
+            self.buffered.push(aligned_read.record)?;
+            if let Some(revcomp_record) = revcomp_record {
+                self.buffered.push(revcomp_record)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Flush the per-read sidecars through to disk, so they survive the run being killed
+    fn checkpoint(&mut self) -> Result<()> {
+        let sidecars = [
+            &mut self.insertions_writer,
+            &mut self.stats_writer,
+            &mut self.stats_json_writer,
+            &mut self.clips_writer,
+            &mut self.bed_writer,
+            &mut self.gff_writer,
+            &mut self.chimera_writer,
+        ];
+        for writer in sidecars.into_iter().flatten() {
+            writer.flush().context("Failed to flush sidecar file")?;
+            writer.get_ref().sync_data().context("Failed to sync sidecar file")?;
+        }
+        Ok(())
+    }
+
+    /// Error if a row isn't the length of the reference it was aligned to
+    fn validate(&self, aligned_read: &AlignedRead) -> Result<()> {
+        let args = self.args;
+        // Unmapped placeholder rows always span the longest contig
+        let expected = match self.reference.get(&aligned_read.target) {
+            Some(_) if aligned_read.mapped && args.no_pad => aligned_read.end - aligned_read.start,
+            Some(contig) if aligned_read.mapped && !args.pad_equal => contig.sequence.len(),
+            _ => self.reference.max_len(),
+        };
+        let expected = if args.translate {
+            expected.saturating_sub(args.frame as usize) / 3
+        } else {
+            expected
+        };
+        let expected = match args.reference_offset {
+            offset if offset < 0 => expected.saturating_sub(offset.unsigned_abs() as usize),
+            offset => expected + offset as usize,
+        };
+        let actual = aligned_read.record.sequence().len();
+        if actual != expected {
+            return Err(anyhow!(
+                "Output row for {} has length {} but expected {}",
+                String::from_utf8_lossy(aligned_read.record.name()), actual, expected
+            ));
+        }
+        Ok(())
+    }
+
+    /// Finish the current output chunk and start writing to the next numbered file
+    fn next_chunk(&mut self) -> Result<()> {
+        self.chunk += 1;
+        self.chunk_records = 0;
+        let path = writer::chunk_path(&self.args.output, self.chunk);
+        let next = fasta::Writer::new(writer::open_output(&path, self.args.bgzf, self.args.append, self.args.threads_io)?);
+        let mut previous = std::mem::replace(&mut self.writer, next);
+        previous.get_mut().finish()?;
+        Ok(())
+    }
+
+    /// Write out the buffered formats and sidecars once every read has been seen
+    pub fn finish(mut self) -> Result<()> {
+        let args = self.args;
+        let collapsed = std::mem::take(&mut self.collapsed).into_records();
+        match args.out_format {
+            cli::OutFormat::Fasta => {
+                let writer = &mut self.writer;
+                self.profile.time(Stage::Write, || collapsed.iter().try_for_each(|record| writer.write_record(record)))
+                    .context("Failed to write aligned record")?;
+            }
+            _ => {
+                for record in collapsed {
+                    self.buffered.push(record)?;
+                }
+            }
+        }
+
+        let writer = self.writer.get_mut();
+        match args.out_format {
+            cli::OutFormat::Fasta | cli::OutFormat::Bed | cli::OutFormat::Paf => {}
+            cli::OutFormat::Matrix => {
+                self.profile.time(Stage::Write, || matrix::write_matrix(writer, &mut self.buffered, args.coordinate_base))?;
+            }
+            cli::OutFormat::Stockholm | cli::OutFormat::Html | cli::OutFormat::Pretty => {
+                // Rows only line up with the reference when there is a single contig
+                let reference_row = match self.reference.contigs.as_slice() {
+                    [contig] if !args.translate && args.reference_offset == 0 => Some(contig.sequence.as_slice()),
+                    _ => None,
+                };
+                let buffered = &mut self.buffered;
+                self.profile.time(Stage::Write, || match args.out_format {
+                    cli::OutFormat::Html => html::write_html(writer, buffered, reference_row),
+                    cli::OutFormat::Pretty => pretty::write_pretty(writer, buffered, reference_row),
+                    _ => stockholm::write_stockholm(writer, buffered, reference_row),
+                })?;
+            }
+        }
+        writer.finish()?;
+        if let Some(writer) = self.reverse_writer.as_mut() {
+            writer.get_mut().finish().context("Failed to write reverse strand output file")?;
+        }
+
+        if let Some(writer) = self.insertions_writer.as_mut() {
+            writer.flush().context("Failed to write insertions file")?;
+        }
+        if let Some(writer) = self.stats_writer.as_mut() {
+            writer.flush().context("Failed to write metrics file")?;
+        }
+        if let Some(writer) = self.stats_json_writer.as_mut() {
+            writer.flush().context("Failed to write metrics JSON")?;
+        }
+        if let Some(writer) = self.clips_writer.as_mut() {
+            writer.flush().context("Failed to write clip report")?;
+        }
+        if let Some(writer) = self.bed_writer.as_mut() {
+            writer.flush().context("Failed to write BED file")?;
+        }
+        if let Some(writer) = self.gff_writer.as_mut() {
+            writer.flush().context("Failed to write GFF file")?;
+        }
+        if let Some(writer) = self.chimera_writer.as_mut() {
+            writer.flush().context("Failed to write chimera report")?;
+        }
+        if let (Some(path), Some(rows)) = (&args.pairwise_identity, self.pairwise_rows.as_mut()) {
+            self.profile.time(Stage::Write, || pairwise::write_pairwise_identity(path, rows))?;
+        }
+        if let Some(consensus_path) = &args.consensus {
+            consensus::write_consensus(consensus_path, &self.consensus_groups, self.reference, args.consensus_fill_ref, args.consensus_tie)?;
+        }
+        if let Some(counts) = &self.msa_counts {
+            match counts.identity_to_consensus() {
+                Some((identity, columns)) => logging::report!("MSA identity to consensus: {:.2}% over {} columns", identity * 100.0, columns),
+                None => logging::report!("MSA identity to consensus: no columns with a consensus base"),
+            }
+        }
+        if let (Some(path), Some(pileup)) = (&args.pileup, &self.pileup) {
+            pileup.write(path, self.reference, args.coordinate_base)?;
+        }
+        if let Some(freq_path) = &args.consensus_freq {
+            consensus::write_consensus_freq(freq_path, &self.consensus_groups, args.coordinate_base)?;
+        }
+        Ok(())
+    }
+}
+
+/// Releases items in input order when ordering is enabled, otherwise as soon as they arrive
+pub struct Reorder<T> {
+    enabled: bool,
+    next: usize,
+    pending: BTreeMap<usize, T>,
+}
+
+impl<T> Reorder<T> {
+    pub fn new(enabled: bool) -> Self {
+        Self { enabled, next: 0, pending: BTreeMap::new() }
+    }
+
+    /// Add the item with the given input index, returning any items that are now ready
+    pub fn push(&mut self, index: usize, item: T) -> Vec<T> {
+        if !self.enabled {
+            return vec![item];
+        }
+        self.pending.insert(index, item);
+        let mut ready = Vec::new();
+        while let Some(item) = self.pending.remove(&self.next) {
+            ready.push(item);
+            self.next += 1;
+        }
+        ready
+    }
+}
+
+/// Shift a row by `offset` columns, adding leading `padding` when positive and dropping leading columns when negative
+fn shift_row(record: &fasta::Record, offset: i64, padding: u8) -> fasta::Record {
+    let row = record.sequence().as_ref();
+    let shift = offset.unsigned_abs() as usize;
+    let sequence = if offset < 0 {
+        row.get(shift..).unwrap_or_default().to_vec()
+    } else {
+        let mut shifted = vec![padding; shift];
+        shifted.extend_from_slice(row);
+        shifted
+    };
+    fasta::Record::new(record.definition().clone(), Sequence::from(sequence))
+}
+
+/// BED line for a mapped read, always 0-based half-open whatever --coordinate-base is
+fn write_bed(writer: &mut impl Write, aligned_read: &AlignedRead, target: &str, offset: usize) -> std::io::Result<()> {
+    writeln!(
+        writer, "{}\t{}\t{}\t{}\t{}\t{}",
+        target,
+        aligned_read.start + offset,
+        aligned_read.end + offset,
+        String::from_utf8_lossy(aligned_read.record.name()),
+        aligned_read.mapq,
+        aligned_read.strand,
+    )
+}
+
+/// GFF3 match feature for a mapped read, 1-based inclusive like every GFF file
+/// `index` keeps the feature IDs unique when read names repeat
+fn write_gff(writer: &mut impl Write, aligned_read: &AlignedRead, target: &str, index: u64, offset: usize) -> std::io::Result<()> {
+    let identity = aligned_read.query.identity();
+    writeln!(
+        writer, "{}\tfastalign\tmatch\t{}\t{}\t{:.4}\t{}\t.\tID=match{};Name={};identity={:.4};mapq={}",
+        gff_escape(target),
+        aligned_read.start + offset + 1,
+        aligned_read.end + offset,
+        identity,
+        aligned_read.strand,
+        index,
+        gff_escape(&String::from_utf8_lossy(aligned_read.record.name())),
+        identity,
+        aligned_read.mapq,
+    )
+}
+
+/// Percent-encode the characters GFF3 reserves in columns and attribute values
+fn gff_escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            c if c.is_control() || "%;=&,".contains(c) => escaped.push_str(&format!("%{:02X}", c as u32)),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// PAF line for a mapped read, the pairwise format minimap2 writes itself
+fn write_paf(writer: &mut impl Write, aligned_read: &AlignedRead, target: &str, target_len: usize, offset: usize) -> std::io::Result<()> {
+    let query = &aligned_read.query;
+    writeln!(
+        writer, "{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}",
+        String::from_utf8_lossy(aligned_read.record.name()),
+        query.len,
+        query.start,
+        query.end,
+        aligned_read.strand,
+        target,
+        target_len,
+        aligned_read.start + offset,
+        aligned_read.end + offset,
+        query.residue_matches,
+        query.block_len,
+        aligned_read.mapq,
+    )
+}
+
+/// Unique aligned rows in the order they were first seen, along with how many reads produced each
+#[derive(Default)]
+struct Collapsed {
+    index: HashMap<Vec<u8>, usize>,
+    records: Vec<(fasta::Record, usize)>,
+}
+
+impl Collapsed {
+    fn add(&mut self, record: fasta::Record) {
+        match self.index.get(record.sequence().as_ref()) {
+            Some(&i) => self.records[i].1 += 1,
+            None => {
+                self.index.insert(record.sequence().as_ref().to_vec(), self.records.len());
+                self.records.push((record, 1));
+            }
+        }
+    }
+
+    /// The first record for each row, with the read count added to its header
+    fn into_records(self) -> Vec<fasta::Record> {
+        self.records.into_iter().map(|(record, size)| {
+            let mut description = record.description().map(<[u8]>::to_vec).unwrap_or_default();
+            if !description.is_empty() {
+                description.push(b' ');
+            }
+            description.extend_from_slice(format!("size={}", size).as_bytes());
+            let definition = Definition::new(record.name().to_vec(), Some(description));
+            fasta::Record::new(definition, record.sequence().clone())
+        }).collect()
+    }
+}
+
+/// Reverse complement of an aligned row, named with an `_rc` suffix
+fn reverse_complement_record(record: &fasta::Record) -> fasta::Record {
+    let mut name = record.name().to_vec();
+    name.extend_from_slice(b"_rc");
+    let sequence = alphabet::reverse_complement(record.sequence().as_ref());
+    fasta::Record::new(Definition::new(name, None), Sequence::from(sequence))
+}
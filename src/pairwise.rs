@@ -0,0 +1,51 @@
+use std::io::{BufWriter, Write};
+use std::fs::File;
+use anyhow::{Result, Context};
+use crate::rows::RowStore;
+
+/// Fraction of identical bases over the columns where neither row has a gap, None if they share none
+fn pairwise_identity(a: &[u8], b: &[u8]) -> Option<f64> {
+    let (mut shared, mut identical) = (0, 0);
+    for (x, y) in a.iter().zip(b) {
+        if !matches!(x, b'-' | b'.') && !matches!(y, b'-' | b'.') {
+            shared += 1;
+            if x.eq_ignore_ascii_case(y) {
+                identical += 1;
+            }
+        }
+    }
+    (shared > 0).then(|| identical as f64 / shared as f64)
+}
+
+/// Write the square matrix of identities between every pair of rows as TSV, NA where two rows don't overlap
+/// Every pair is compared, so this is quadratic in the number of reads
+pub fn write_pairwise_identity(path: &str, rows: &mut RowStore) -> Result<()> {
+    let count = rows.count();
+    let mut identities = vec![None; count * count];
+    for i in 0..count {
+        let row = rows.row(i)?.into_owned();
+        identities[i * count + i] = pairwise_identity(&row, &row);
+        for j in (i + 1)..count {
+            let identity = pairwise_identity(&row, &rows.row(j)?);
+            identities[i * count + j] = identity;
+            identities[j * count + i] = identity;
+        }
+    }
+
+    let file = File::create(path).context("Failed to create pairwise identity file")?;
+    let mut writer = BufWriter::new(file);
+    let names: Vec<String> = rows.names().iter().map(|name| String::from_utf8_lossy(name).into_owned()).collect();
+    writeln!(writer, "name\t{}", names.join("\t"))?;
+    for (i, name) in names.iter().enumerate() {
+        write!(writer, "{}", name)?;
+        for identity in &identities[i * count..(i + 1) * count] {
+            match identity {
+                Some(identity) => write!(writer, "\t{:.4}", identity)?,
+                None => write!(writer, "\tNA")?,
+            }
+        }
+        writeln!(writer)?;
+    }
+    writer.flush().context("Failed to write pairwise identity file")?;
+    Ok(())
+}
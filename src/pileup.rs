@@ -0,0 +1,43 @@
+use std::collections::HashMap;
+use std::io::Write;
+use anyhow::{Result, Context};
+use crate::consensus::ColumnCounts;
+use crate::reference::Reference;
+use crate::sidecar;
+
+/// Base counts at every reference position, built up from the aligned rows
+#[derive(Default)]
+pub struct Pileup {
+    contigs: HashMap<String, ColumnCounts>,
+}
+
+impl Pileup {
+    /// Count the bases of a row over the reference span the read covers
+    /// Columns outside `start..end` are padding, so they don't add to the depth
+    pub fn add(&mut self, target: &str, row: &[u8], start: usize, end: usize) {
+        self.contigs.entry(target.to_string()).or_default().add_span(row, start..end);
+    }
+
+    /// Write one line per reference position with the reference base and the count of each base, in reference order
+    pub fn write(&self, path: &str, reference: &Reference, coordinate_base: u8) -> Result<()> {
+        let mut writer = sidecar::create_sidecar(path)?;
+        writeln!(writer, "reference\tposition\tref_base\tA\tC\tG\tT\tN\tgap\tdepth")?;
+        for contig in &reference.contigs {
+            let counts = self.contigs.get(&contig.name);
+            for (offset, &ref_base) in contig.sequence.iter().enumerate() {
+                let column = counts.map_or([0; 6], |counts| counts.column(offset));
+                let depth: usize = column.iter().sum();
+                writeln!(
+                    writer, "{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}",
+                    contig.source_name,
+                    sidecar::position(contig.offset + offset, coordinate_base),
+                    ref_base as char,
+                    column[0], column[1], column[2], column[3], column[4], column[5],
+                    depth,
+                )?;
+            }
+        }
+        writer.flush().context("Failed to write pileup file")?;
+        Ok(())
+    }
+}
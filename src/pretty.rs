@@ -0,0 +1,52 @@
+use std::io::Write;
+use anyhow::{Result, Context};
+use crate::rows::RowStore;
+
+/// Alignment columns per block
+const BLOCK_WIDTH: usize = 60;
+
+/// Write the alignment in wrapped blocks, Clustal style, with the reference on top and a conservation line below
+/// Columns where every row with a base (and the reference) agree are marked `*`, columns without any bases are left blank
+pub fn write_pretty<W: Write>(writer: &mut W, rows: &mut RowStore, reference: Option<&[u8]>) -> Result<()> {
+    let names: Vec<String> = rows.names().iter().map(|name| String::from_utf8_lossy(name).into_owned()).collect();
+    let width = names.iter().map(|name| name.len()).chain(["reference".len()]).max().unwrap_or(0);
+    let columns = rows.max_len().max(reference.map_or(0, <[u8]>::len));
+
+    for block_start in (0..columns).step_by(BLOCK_WIDTH) {
+        let block = block_start..(block_start + BLOCK_WIDTH).min(columns);
+        let mut block_rows = Vec::with_capacity(rows.count());
+        for index in 0..rows.count() {
+            block_rows.push(rows.read(index, block.clone())?.into_owned());
+        }
+        let reference_block = reference.map(|reference| &reference[block.start.min(reference.len())..block.end.min(reference.len())]);
+
+        if let Some(reference_block) = reference_block {
+            writeln!(writer, "{:width$} {}", "reference", String::from_utf8_lossy(reference_block), width = width)?;
+        }
+        for (name, row) in names.iter().zip(&block_rows) {
+            writeln!(writer, "{:width$} {}", name, String::from_utf8_lossy(row), width = width)?;
+        }
+        let conservation: String = (0..block.len())
+            .map(|column| {
+                let bases = reference_block.iter().map(|reference| reference.get(column))
+                    .chain(block_rows.iter().map(|row| row.get(column)))
+                    .flatten()
+                    .filter(|&&base| base != b'-' && base != b'.')
+                    .map(u8::to_ascii_uppercase);
+                if is_conserved(bases) { '*' } else { ' ' }
+            })
+            .collect();
+        writeln!(writer, "{:width$} {}", "", conservation.trim_end(), width = width)?;
+        writeln!(writer)?;
+    }
+    writer.flush().context("Failed to write pretty alignment")?;
+    Ok(())
+}
+
+/// Whether every base is the same, needing at least one
+fn is_conserved(mut bases: impl Iterator<Item = u8>) -> bool {
+    match bases.next() {
+        Some(first) => bases.all(|base| base == first),
+        None => false,
+    }
+}
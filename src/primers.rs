@@ -0,0 +1,51 @@
+use anyhow::{Result, Context};
+use noodles::fasta;
+use crate::{alphabet, reader};
+
+/// Primer sequences trimmed from the ends of reads before alignment
+pub struct Primers {
+    /// Every primer along with its reverse complement, so reads in either orientation are trimmed
+    sequences: Vec<Vec<u8>>,
+    max_mismatches: usize,
+}
+
+impl Primers {
+    pub fn load(path: &str, max_mismatches: usize) -> Result<Self> {
+        let mut primer_reader = fasta::Reader::new(reader::open_decompressed(path)?);
+        let mut sequences = Vec::new();
+        for record in primer_reader.records() {
+            let record = record.context("Failed to read primer FASTA record")?;
+            let primer = record.sequence().as_ref().to_ascii_uppercase();
+            sequences.push(alphabet::reverse_complement(&primer));
+            sequences.push(primer);
+        }
+        sequences.retain(|primer| !primer.is_empty());
+        Ok(Self { sequences, max_mismatches })
+    }
+
+    /// Range of the read left after removing the longest primer matching each end, None if nothing was trimmed
+    /// A read that is all primer is left alone rather than trimmed to nothing
+    pub fn trim(&self, sequence: &[u8]) -> Option<std::ops::Range<usize>> {
+        let start = self.longest_match(|primer| sequence.get(..primer.len()));
+        let end = sequence.len() - self.longest_match(|primer| {
+            sequence.len().checked_sub(primer.len()).map(|offset| &sequence[offset..])
+        });
+        if (start == 0 && end == sequence.len()) || start >= end {
+            return None;
+        }
+        Some(start..end)
+    }
+
+    /// Length of the longest primer matching the read end picked out by `read_end`, 0 if none do
+    fn longest_match<'a>(&self, read_end: impl Fn(&[u8]) -> Option<&'a [u8]>) -> usize {
+        self.sequences.iter()
+            .filter(|primer| read_end(primer).is_some_and(|bases| mismatches(primer, bases) <= self.max_mismatches))
+            .map(|primer| primer.len())
+            .max()
+            .unwrap_or(0)
+    }
+}
+
+fn mismatches(primer: &[u8], bases: &[u8]) -> usize {
+    primer.iter().zip(bases).filter(|(p, b)| !p.eq_ignore_ascii_case(b)).count()
+}
@@ -0,0 +1,70 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+use crate::logging;
+
+/// Pipeline stages that are timed for `--profile`
+pub enum Stage {
+    Read,
+    Align,
+    Write,
+}
+
+/// Accumulated time spent in each pipeline stage, shared between threads
+#[derive(Default)]
+pub struct Profile {
+    read_nanos: AtomicU64,
+    align_nanos: AtomicU64,
+    write_nanos: AtomicU64,
+    records: AtomicU64,
+    bases: AtomicU64,
+}
+
+impl Profile {
+    /// Run `f` and add its elapsed time to the given stage
+    pub fn time<T>(&self, stage: Stage, f: impl FnOnce() -> T) -> T {
+        let start = Instant::now();
+        let result = f();
+        let counter = match stage {
+            Stage::Read => &self.read_nanos,
+            Stage::Align => &self.align_nanos,
+            Stage::Write => &self.write_nanos,
+        };
+        counter.fetch_add(start.elapsed().as_nanos() as u64, Ordering::Relaxed);
+        result
+    }
+
+    /// Count a record read from the input
+    pub fn add_record(&self, bases: usize) {
+        self.records.fetch_add(1, Ordering::Relaxed);
+        self.bases.fetch_add(bases as u64, Ordering::Relaxed);
+    }
+
+    /// Print the per-stage breakdown to stderr
+    /// Align time is summed across workers, so it can exceed the wall time
+    pub fn report(&self, wall_time: Duration, num_threads: usize) {
+        let seconds = |counter: &AtomicU64| Duration::from_nanos(counter.load(Ordering::Relaxed)).as_secs_f64();
+        let wall = wall_time.as_secs_f64();
+        let records = self.records.load(Ordering::Relaxed) as f64;
+        let bases = self.bases.load(Ordering::Relaxed) as f64;
+
+        logging::report!("Profile:");
+        logging::report!("  reading:  {:.3}s", seconds(&self.read_nanos));
+        logging::report!("  aligning: {:.3}s (summed across {} workers)", seconds(&self.align_nanos), num_threads);
+        logging::report!("  writing:  {:.3}s", seconds(&self.write_nanos));
+        logging::report!("  wall:     {:.3}s", wall);
+        if wall > 0.0 {
+            logging::report!("  throughput: {:.1} reads/s, {:.1} bases/s", records / wall, bases / wall);
+        }
+    }
+
+    /// Print the input throughput to stderr, for --benchmark-mode
+    pub fn report_throughput(&self, wall_time: Duration) {
+        let wall = wall_time.as_secs_f64();
+        let records = self.records.load(Ordering::Relaxed) as f64;
+        let megabases = self.bases.load(Ordering::Relaxed) as f64 / 1e6;
+        logging::report!("Benchmark: {:.0} reads in {:.3}s", records, wall);
+        if wall > 0.0 {
+            logging::report!("  throughput: {:.1} reads/s, {:.2} MB/s", records / wall, megabases / wall);
+        }
+    }
+}
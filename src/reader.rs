@@ -0,0 +1,113 @@
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Read};
+use anyhow::{Result, Context};
+use flate2::read::MultiGzDecoder;
+use noodles::{fasta, fastq};
+use noodles::fasta::record::{Definition, Sequence};
+use crate::cli::InputFormat;
+
+const UTF8_BOM: &[u8] = b"\xEF\xBB\xBF";
+const GZIP_MAGIC: &[u8] = b"\x1F\x8B";
+
+/// Reader that strips a leading UTF-8 BOM and drops carriage returns, normalising CRLF to LF
+/// Stops stray `\r` bytes from ending up in sequence data
+pub struct SanitizedReader<R> {
+    inner: R,
+    at_start: bool,
+}
+
+impl<R: BufRead> SanitizedReader<R> {
+    pub fn new(inner: R) -> Self {
+        Self { inner, at_start: true }
+    }
+}
+
+impl<R: BufRead> Read for SanitizedReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.at_start {
+            self.at_start = false;
+            if self.inner.fill_buf()?.starts_with(UTF8_BOM) {
+                self.inner.consume(UTF8_BOM.len());
+            }
+        }
+
+        loop {
+            let n = self.inner.read(buf)?;
+            if n == 0 {
+                return Ok(0);
+            }
+            // Remove carriage returns in place, keep reading if the whole chunk was removed
+            let mut written = 0;
+            for i in 0..n {
+                if buf[i] != b'\r' {
+                    buf[written] = buf[i];
+                    written += 1;
+                }
+            }
+            if written > 0 {
+                return Ok(written);
+            }
+        }
+    }
+}
+
+/// Open an input file for reading, or stdin for `-`, optionally sanitizing line endings and BOM
+pub fn open_input(path: &str, sanitize: bool) -> Result<Box<dyn BufRead + Send>> {
+    let input: Box<dyn Read + Send> = match path {
+        "-" => Box::new(io::stdin()),
+        path => Box::new(File::open(path).context("Failed to open input file")?),
+    };
+    if sanitize {
+        Ok(Box::new(BufReader::new(SanitizedReader::new(BufReader::new(input)))))
+    } else {
+        Ok(Box::new(BufReader::new(input)))
+    }
+}
+
+/// Reads from either FASTA or FASTQ input
+pub enum InputReader {
+    Fasta(fasta::Reader<Box<dyn BufRead + Send>>),
+    Fastq(fastq::Reader<Box<dyn BufRead + Send>>),
+}
+
+impl InputReader {
+    /// Open the reads, detecting the format from the first byte unless one is given
+    pub fn open(path: &str, sanitize: bool, format: Option<InputFormat>) -> Result<Self> {
+        let mut input = open_input(path, sanitize)?;
+        let format = match format {
+            Some(format) => format,
+            None => match input.fill_buf().context("Failed to read input file")?.first() {
+                Some(b'@') => InputFormat::Fastq,
+                _ => InputFormat::Fasta,
+            },
+        };
+        Ok(match format {
+            InputFormat::Fasta => InputReader::Fasta(fasta::Reader::new(input)),
+            InputFormat::Fastq => InputReader::Fastq(fastq::Reader::new(input)),
+        })
+    }
+
+    /// Iterate over the reads as FASTA records, FASTQ qualities are dropped
+    pub fn records(&mut self) -> Box<dyn Iterator<Item = io::Result<fasta::Record>> + '_> {
+        match self {
+            InputReader::Fasta(reader) => Box::new(reader.records()),
+            InputReader::Fastq(reader) => Box::new(reader.records().map(|record| record.map(|record| {
+                let description = record.description();
+                let description = (!description.is_empty()).then(|| description.to_vec());
+                let definition = Definition::new(record.name().to_vec(), description);
+                fasta::Record::new(definition, Sequence::from(record.sequence().to_vec()))
+            }))),
+        }
+    }
+}
+
+/// Open a file for reading, transparently decompressing it if it starts with the gzip magic bytes
+pub fn open_decompressed(path: &str) -> Result<Box<dyn BufRead + Send>> {
+    let file = File::open(path).context(format!("Failed to open file: {}", path))?;
+    let mut reader = BufReader::new(file);
+    if reader.fill_buf().context(format!("Failed to read file: {}", path))?.starts_with(GZIP_MAGIC) {
+        Ok(Box::new(BufReader::new(MultiGzDecoder::new(reader))))
+    } else {
+        Ok(Box::new(reader))
+    }
+}
@@ -0,0 +1,224 @@
+use std::collections::{HashMap, HashSet};
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use anyhow::{Result, Context, anyhow};
+use noodles::fasta;
+use noodles::core::Region;
+use noodles::fasta::record::{Definition, Sequence};
+use crate::reader;
+
+/// A single reference sequence that reads can be aligned against
+pub struct Contig {
+    pub name: String,
+    pub sequence: Vec<u8>,
+    /// Name of the original contig, which differs from `name` only for --split-on-n pieces
+    pub source_name: String,
+    /// Where the sequence starts on the original contig, non-zero for a --region or a --split-on-n piece
+    pub offset: usize,
+    /// Length of the original contig
+    pub source_len: usize,
+}
+
+/// All reference contigs, looked up by the target name minimap2 reports
+#[derive(Default)]
+pub struct Reference {
+    pub contigs: Vec<Contig>,
+    index: HashMap<String, usize>,
+    /// Whether whitespace was removed from any loaded sequence, so the files no longer match the coordinates
+    pub stripped_whitespace: bool,
+}
+
+impl Reference {
+    /// Load every record from each reference FASTA file
+    pub fn load(paths: &[String]) -> Result<Self> {
+        let mut reference = Reference::default();
+        for path in paths {
+            let mut ref_reader = fasta::Reader::new(reader::open_decompressed(path)?);
+            for record in ref_reader.records() {
+                let record = record.context("Failed to read reference FASTA record")?;
+                let name = std::str::from_utf8(record.name()).context("Invalid UTF-8 reference name")?;
+                // noodles drops line breaks, but carriage returns and stray spaces would still shift coordinates
+                let mut sequence = record.sequence().as_ref().to_vec();
+                let len = sequence.len();
+                sequence.retain(|base| !base.is_ascii_whitespace());
+                reference.stripped_whitespace |= sequence.len() != len;
+                reference.push(name.to_string(), sequence)?;
+            }
+        }
+        Ok(reference)
+    }
+
+    /// Load a single region, e.g. `chr1:1000-2000`, keeping the contig name and where the region starts
+    /// With a .fai index next to the FASTA only the region is read, otherwise the whole file is loaded and sliced
+    pub fn load_region(path: &str, spec: &str) -> Result<Self> {
+        let region: Region = spec.parse().context(format!("Invalid region: {}", spec))?;
+        let name = String::from_utf8_lossy(region.name()).into_owned();
+        let interval = region.interval();
+        let start = interval.start().map_or(0, |start| usize::from(start) - 1);
+        let fai_path = format!("{}.fai", path);
+        let (sequence, source_len) = if std::path::Path::new(&fai_path).exists() {
+            let mut indexed_reader = fasta::io::indexed_reader::Builder::default()
+                .build_from_path(path)
+                .context(format!("Failed to open indexed reference: {}", path))?;
+            let record = indexed_reader.query(&region).context(format!("Failed to read region {} from {}", spec, path))?;
+            let source_len = indexed_reader.index().as_ref().iter()
+                .find(|record| {
+                    let record_name: &[u8] = record.name().as_ref();
+                    record_name == name.as_bytes()
+                })
+                .map_or(0, |record| record.length() as usize);
+            (record.sequence().as_ref().to_vec(), source_len)
+        } else {
+            let whole = Reference::load(&[path.to_string()])?;
+            let contig = whole.get(&name).ok_or_else(|| anyhow!("Reference contig not found: {}", name))?;
+            let end = interval.end().map_or(contig.sequence.len(), usize::from).min(contig.sequence.len());
+            let sequence = contig.sequence.get(start..end)
+                .ok_or_else(|| anyhow!("Region {} is outside the {} bases of {}", spec, contig.sequence.len(), name))?
+                .to_vec();
+            (sequence, contig.sequence.len())
+        };
+
+        let mut reference = Reference::default();
+        reference.push(name, sequence)?;
+        if let Some(contig) = reference.contigs.last_mut() {
+            contig.offset = start;
+            contig.source_len = source_len.max(start + contig.sequence.len());
+        }
+        Ok(reference)
+    }
+
+    /// Add a contig, names must be unique across all reference files
+    pub fn push(&mut self, name: String, sequence: Vec<u8>) -> Result<()> {
+        if self.index.contains_key(&name) {
+            return Err(anyhow!("Duplicate reference sequence name: {}", name));
+        }
+        self.index.insert(name.clone(), self.contigs.len());
+        let source_len = sequence.len();
+        self.contigs.push(Contig { source_name: name.clone(), name, sequence, offset: 0, source_len });
+        Ok(())
+    }
+
+    /// Keep only the named contigs, erroring if any of them are missing
+    pub fn retain(&mut self, names: &HashSet<String>) -> Result<()> {
+        if let Some(missing) = names.iter().find(|name| !self.index.contains_key(*name)) {
+            return Err(anyhow!("Reference contig not found: {}", missing));
+        }
+        let contigs = std::mem::take(&mut self.contigs);
+        self.index.clear();
+        for contig in contigs.into_iter().filter(|contig| names.contains(&contig.name)) {
+            self.push(contig.name, contig.sequence)?;
+        }
+        Ok(())
+    }
+
+    /// Split contigs on runs of at least `min_run` Ns into separate targets
+    /// Contigs that are split are named `<name>_1`, `<name>_2`, ... in order, each keeping where it starts on the original contig
+    pub fn split_on_n(self, min_run: usize) -> Result<Self> {
+        let mut split = Reference::default();
+        for contig in self.contigs {
+            let pieces = split_sequence_on_n(&contig.sequence, min_run);
+            if pieces.len() == 1 && pieces[0].0 == 0 {
+                split.push(contig.name, contig.sequence)?;
+                continue;
+            }
+            for (i, (start, piece)) in pieces.into_iter().enumerate() {
+                split.push(format!("{}_{}", contig.name, i + 1), piece.to_vec())?;
+                if let Some(piece) = split.contigs.last_mut() {
+                    piece.source_name = contig.name.clone();
+                    piece.offset = start;
+                    piece.source_len = contig.sequence.len();
+                }
+            }
+        }
+        Ok(split)
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Contig> {
+        self.index.get(name).map(|&i| &self.contigs[i])
+    }
+
+    /// Original contig of the target `name` and the offset to add to a position on it to place it there
+    pub fn locate<'a>(&'a self, name: &'a str) -> (&'a str, usize) {
+        self.get(name).map_or((name, 0), |contig| (contig.source_name.as_str(), contig.offset))
+    }
+
+    /// Combined length of every contig
+    pub fn total_len(&self) -> usize {
+        self.contigs.iter().map(|contig| contig.sequence.len()).sum()
+    }
+
+    /// Length of the longest contig
+    pub fn max_len(&self) -> usize {
+        self.contigs.iter().map(|contig| contig.sequence.len()).max().unwrap_or(0)
+    }
+
+    /// Write all contigs to a single FASTA file so they can be indexed together
+    pub fn write_fasta(&self, file: &mut File) -> Result<()> {
+        let mut writer = fasta::Writer::new(BufWriter::new(file));
+        for contig in &self.contigs {
+            let definition = Definition::new(contig.name.clone().into_bytes(), None);
+            let sequence = Sequence::from(contig.sequence.clone());
+            writer.write_record(&fasta::Record::new(definition, sequence))
+                .context("Failed to write combined reference record")?;
+        }
+        writer.get_mut().flush().context("Failed to write combined reference file")?;
+        Ok(())
+    }
+}
+
+/// Parse a list of contig or read names, either a file with one name per line or a comma separated list
+pub fn parse_name_list(spec: &str) -> Result<HashSet<String>> {
+    let names = if std::path::Path::new(spec).is_file() {
+        std::fs::read_to_string(spec).context(format!("Failed to read name list: {}", spec))?
+            .lines()
+            .map(|line| line.trim().to_string())
+            .filter(|line| !line.is_empty())
+            .collect()
+    } else {
+        spec.split(',').map(|name| name.trim().to_string()).filter(|name| !name.is_empty()).collect()
+    };
+    Ok(names)
+}
+
+/// Read a two column TSV of old and new names, skipping blank lines and # comments
+pub fn parse_rename_map(path: &str) -> Result<HashMap<Vec<u8>, Vec<u8>>> {
+    let contents = std::fs::read_to_string(path).context(format!("Failed to read rename map: {}", path))?;
+    let mut renames = HashMap::new();
+    for (line_number, line) in contents.lines().enumerate() {
+        let line = line.trim_end();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let (old, new) = line.split_once('\t')
+            .ok_or_else(|| anyhow!("Rename map line {} needs an old and a new name separated by a tab", line_number + 1))?;
+        if renames.insert(old.as_bytes().to_vec(), new.trim().as_bytes().to_vec()).is_some() {
+            return Err(anyhow!("Duplicate name in rename map: {}", old));
+        }
+    }
+    Ok(renames)
+}
+
+/// Split a sequence on runs of at least `min_run` Ns, dropping the runs and any empty pieces
+/// Each piece comes with the position it starts at
+fn split_sequence_on_n(sequence: &[u8], min_run: usize) -> Vec<(usize, &[u8])> {
+    let mut pieces = Vec::new();
+    let mut piece_start = 0;
+    let mut pos = 0;
+    while pos < sequence.len() {
+        if !sequence[pos].eq_ignore_ascii_case(&b'N') {
+            pos += 1;
+            continue;
+        }
+        let run_start = pos;
+        while pos < sequence.len() && sequence[pos].eq_ignore_ascii_case(&b'N') {
+            pos += 1;
+        }
+        if pos - run_start >= min_run {
+            pieces.push((piece_start, &sequence[piece_start..run_start]));
+            piece_start = pos;
+        }
+    }
+    pieces.push((piece_start, &sequence[piece_start..]));
+    pieces.retain(|(_, piece)| !piece.is_empty());
+    pieces
+}
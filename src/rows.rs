@@ -0,0 +1,146 @@
+use std::borrow::Cow;
+use std::fs::File;
+use std::io::{BufWriter, Read, Seek, SeekFrom, Write};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use anyhow::{Result, Context};
+use noodles::fasta;
+use tempfile::NamedTempFile;
+
+/// Aligned rows held until the whole alignment can be written, in memory or spilled to a temporary file
+pub struct RowStore {
+    names: Vec<Vec<u8>>,
+    lengths: Vec<usize>,
+    backing: Backing,
+    label: String,
+    spill_dir: Option<PathBuf>,
+    // Rows move to disk once they'd take more than this many bytes in memory
+    max_memory: Option<usize>,
+    memory: usize,
+}
+
+enum Backing {
+    Memory(Vec<Vec<u8>>),
+    Disk(SpillFile),
+}
+
+/// Rows written back to back, read back by offset
+struct SpillFile {
+    // Removed when the store is dropped
+    file: NamedTempFile,
+    writer: BufWriter<File>,
+    reader: Option<File>,
+    offsets: Vec<u64>,
+    written: u64,
+}
+
+impl SpillFile {
+    fn create(dir: &Path, label: &str) -> Result<Self> {
+        let file = tempfile::Builder::new()
+            .prefix(&format!("fastalign-{}-", label))
+            .suffix(".tmp")
+            .tempfile_in(dir)
+            .context(format!("Failed to create spill file in {}", dir.display()))?;
+        let writer = BufWriter::new(file.reopen().context("Failed to open spill file")?);
+        Ok(SpillFile { file, writer, reader: None, offsets: Vec::new(), written: 0 })
+    }
+
+    fn push(&mut self, row: &[u8]) -> Result<()> {
+        self.writer.write_all(row).context("Failed to write spill file")?;
+        self.offsets.push(self.written);
+        self.written += row.len() as u64;
+        Ok(())
+    }
+}
+
+impl RowStore {
+    /// Rows are spilled under `spill_dir` when given, otherwise kept in memory
+    /// With `max_memory` in-memory rows are moved to a spill file, under `spill_dir` or the temporary directory, once they outgrow it
+    /// `label` keeps the spill files of different stores apart
+    pub fn new(spill_dir: Option<&str>, label: &str, max_memory: Option<usize>) -> Result<Self> {
+        let backing = match spill_dir {
+            Some(dir) if max_memory.is_none() => Backing::Disk(SpillFile::create(Path::new(dir), label)?),
+            _ => Backing::Memory(Vec::new()),
+        };
+        Ok(Self {
+            names: Vec::new(),
+            lengths: Vec::new(),
+            backing,
+            label: label.to_string(),
+            spill_dir: spill_dir.map(PathBuf::from),
+            max_memory,
+            memory: 0,
+        })
+    }
+
+    pub fn push(&mut self, record: fasta::Record) -> Result<()> {
+        let row = record.sequence().as_ref();
+        if self.max_memory.is_some_and(|max_memory| self.memory + row.len() > max_memory) {
+            self.spill()?;
+        }
+        self.names.push(record.name().to_vec());
+        self.lengths.push(row.len());
+        match &mut self.backing {
+            Backing::Memory(rows) => {
+                self.memory += row.len();
+                rows.push(row.to_vec());
+            }
+            Backing::Disk(spill) => spill.push(row)?,
+        }
+        Ok(())
+    }
+
+    /// Move the rows held in memory to a spill file, later rows are written straight to it
+    fn spill(&mut self) -> Result<()> {
+        let Backing::Memory(rows) = &mut self.backing else {
+            return Ok(());
+        };
+        let dir = self.spill_dir.clone().unwrap_or_else(std::env::temp_dir);
+        log::info!("Buffered {} rows exceed --max-memory, spilling them to {}", self.label, dir.display());
+        let mut spill = SpillFile::create(&dir, &self.label)?;
+        for row in rows.drain(..) {
+            spill.push(&row)?;
+        }
+        self.backing = Backing::Disk(spill);
+        self.memory = 0;
+        Ok(())
+    }
+
+    pub fn names(&self) -> &[Vec<u8>] {
+        &self.names
+    }
+
+    pub fn count(&self) -> usize {
+        self.names.len()
+    }
+
+    /// Length of the longest row
+    pub fn max_len(&self) -> usize {
+        self.lengths.iter().copied().max().unwrap_or(0)
+    }
+
+    /// Part of a row, cut short where the row ends
+    pub fn read(&mut self, index: usize, columns: Range<usize>) -> Result<Cow<'_, [u8]>> {
+        let len = self.lengths[index];
+        let columns = columns.start.min(len)..columns.end.min(len);
+        match &mut self.backing {
+            Backing::Memory(rows) => Ok(Cow::Borrowed(&rows[index][columns])),
+            Backing::Disk(spill) => {
+                if spill.reader.is_none() {
+                    spill.writer.flush().context("Failed to write spill file")?;
+                    spill.reader = Some(spill.file.reopen().context("Failed to open spill file")?);
+                }
+                let reader = spill.reader.as_mut().expect("spill reader was just opened");
+                let mut buffer = vec![0; columns.len()];
+                reader.seek(SeekFrom::Start(spill.offsets[index] + columns.start as u64))?;
+                reader.read_exact(&mut buffer).context("Failed to read spill file")?;
+                Ok(Cow::Owned(buffer))
+            }
+        }
+    }
+
+    /// A whole row
+    pub fn row(&mut self, index: usize) -> Result<Cow<'_, [u8]>> {
+        self.read(index, 0..self.lengths[index])
+    }
+}
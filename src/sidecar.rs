@@ -0,0 +1,15 @@
+use std::fs::File;
+use std::io::BufWriter;
+use anyhow::{Result, Context};
+
+/// Create a sidecar file written alongside the alignment
+pub fn create_sidecar(path: &str) -> Result<BufWriter<File>> {
+    let file = File::create(path).context(format!("Failed to create sidecar file: {}", path))?;
+    Ok(BufWriter::new(file))
+}
+
+/// Convert a 0-based reference offset into the coordinate base used by TSV sidecars
+/// Formats with a fixed convention (e.g. BED is 0-based, VCF is 1-based) should not use this
+pub fn position(offset: usize, coordinate_base: u8) -> usize {
+    offset + coordinate_base as usize
+}
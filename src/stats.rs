@@ -0,0 +1,158 @@
+use std::io::Write;
+use serde::Serialize;
+use crate::{sidecar, AlignedRead, CigarOperation};
+
+/// Per-read alignment metrics derived from the CIGAR
+#[derive(Default, Clone, Debug)]
+pub struct ReadStats {
+    pub matches: usize,
+    pub mismatches: usize,
+    /// Inserted and deleted bases, not the number of events
+    pub insertions: usize,
+    pub deletions: usize,
+    /// Length of the output row the read was aligned into
+    pub row_len: usize,
+    pub read_len: usize,
+    pub gc_content: f64,
+}
+
+impl ReadStats {
+    /// Tally the CIGAR, `=` and `X` are counted directly while mismatches within `M`
+    /// are derived from the edit distance that minimap2 reports
+    pub fn from_cigar(cigar: &[CigarOperation], edit_distance: Option<i32>, row_len: usize) -> Self {
+        let mut stats = ReadStats { row_len, ..Default::default() };
+        let mut unresolved = 0;
+        for op in cigar {
+            match op {
+                CigarOperation::Match(count) => unresolved += count,
+                CigarOperation::Equal(count) => stats.matches += count,
+                CigarOperation::Diff(count) => stats.mismatches += count,
+                CigarOperation::Insertion(count) => stats.insertions += count,
+                CigarOperation::Deletion(count) => stats.deletions += count,
+                _ => {}
+            }
+        }
+        // The edit distance also counts indels and any X operations, the rest are mismatches within M
+        let edit_distance = edit_distance.unwrap_or(0).max(0) as usize;
+        let unresolved_mismatches = edit_distance
+            .saturating_sub(stats.insertions + stats.deletions + stats.mismatches)
+            .min(unresolved);
+        stats.mismatches += unresolved_mismatches;
+        stats.matches += unresolved - unresolved_mismatches;
+        stats
+    }
+
+    /// Add the composition of the whole read, aligned or not
+    pub fn with_read(mut self, sequence: &[u8]) -> Self {
+        self.read_len = sequence.len();
+        self.gc_content = gc_content(sequence);
+        self
+    }
+
+    /// Number of alignment columns, including indels
+    pub fn aligned_length(&self) -> usize {
+        self.matches + self.mismatches + self.insertions + self.deletions
+    }
+
+    /// Reference bases the alignment consumes, zero when every aligned read base is an insertion
+    pub fn reference_span(&self) -> usize {
+        self.matches + self.mismatches + self.deletions
+    }
+
+    /// Fraction of alignment columns that are matches
+    pub fn identity(&self) -> f64 {
+        match self.aligned_length() {
+            0 => 0.0,
+            len => self.matches as f64 / len as f64,
+        }
+    }
+
+    /// Fraction of the output row not covered by an aligned read base
+    pub fn gap_fraction(&self) -> f64 {
+        match self.row_len {
+            0 => 0.0,
+            len => 1.0 - (self.matches + self.mismatches).min(len) as f64 / len as f64,
+        }
+    }
+}
+
+/// One line of the per-read metrics, shared by the TSV and JSON outputs
+#[derive(Serialize)]
+pub struct StatsRow<'a> {
+    pub name: String,
+    pub target: &'a str,
+    pub strand: char,
+    pub start: usize,
+    pub end: usize,
+    pub mapq: u32,
+    pub matches: usize,
+    pub mismatches: usize,
+    pub insertions: usize,
+    pub deletions: usize,
+    pub identity: f64,
+    pub aligned_length: usize,
+    pub gap_fraction: f64,
+    pub read_length: usize,
+    pub gc_content: f64,
+    /// Whether any reference base was consumed, reads without an anchor give an empty row
+    pub reference_anchor: bool,
+}
+
+impl<'a> StatsRow<'a> {
+    /// `target` and `offset` place the coordinates back on the original contig when aligning to a --region or --split-on-n pieces
+    pub fn new(aligned_read: &'a AlignedRead, stats: &ReadStats, coordinate_base: u8, target: &'a str, offset: usize) -> Self {
+        StatsRow {
+            name: String::from_utf8_lossy(aligned_read.record.name()).into_owned(),
+            target,
+            strand: aligned_read.strand,
+            start: sidecar::position(aligned_read.start + offset, coordinate_base),
+            end: aligned_read.end + offset,
+            mapq: aligned_read.mapq,
+            matches: stats.matches,
+            mismatches: stats.mismatches,
+            insertions: stats.insertions,
+            deletions: stats.deletions,
+            identity: stats.identity(),
+            aligned_length: stats.aligned_length(),
+            gap_fraction: stats.gap_fraction(),
+            read_length: stats.read_len,
+            gc_content: stats.gc_content,
+            reference_anchor: stats.reference_span() > 0,
+        }
+    }
+
+    pub fn write_tsv(&self, writer: &mut impl Write) -> std::io::Result<()> {
+        writeln!(
+            writer, "{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{:.4}\t{}\t{:.4}\t{}\t{:.4}\t{}",
+            self.name, self.target, self.strand, self.start, self.end, self.mapq,
+            self.matches, self.mismatches, self.insertions, self.deletions,
+            self.identity, self.aligned_length, self.gap_fraction, self.read_length, self.gc_content,
+            if self.reference_anchor { "yes" } else { "no" },
+        )
+    }
+
+    /// Write the row as a single line of JSON
+    pub fn write_json(&self, writer: &mut impl Write) -> anyhow::Result<()> {
+        serde_json::to_writer(&mut *writer, self)?;
+        writeln!(writer)?;
+        Ok(())
+    }
+}
+
+pub const TSV_HEADER: &str = "name\ttarget\tstrand\tstart\tend\tmapq\tmatches\tmismatches\tinsertions\tdeletions\tidentity\taligned_length\tgap_fraction\tread_length\tgc_content\treference_anchor";
+
+/// Fraction of called bases that are G or C, ignoring Ns and other ambiguity codes
+fn gc_content(sequence: &[u8]) -> f64 {
+    let (mut gc, mut called) = (0, 0);
+    for base in sequence {
+        match base.to_ascii_uppercase() {
+            b'G' | b'C' => {
+                gc += 1;
+                called += 1;
+            }
+            b'A' | b'T' | b'U' => called += 1,
+            _ => {}
+        }
+    }
+    if called == 0 { 0.0 } else { gc as f64 / called as f64 }
+}
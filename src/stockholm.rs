@@ -0,0 +1,27 @@
+use std::io::Write;
+use anyhow::{Result, Context};
+use crate::consensus::ColumnCounts;
+use crate::rows::RowStore;
+
+/// Write the alignment in Stockholm format with a consensus line and, when given, the reference as the RF line
+pub fn write_stockholm<W: Write>(writer: &mut W, rows: &mut RowStore, reference: Option<&[u8]>) -> Result<()> {
+    let mut counts = ColumnCounts::default();
+    for index in 0..rows.count() {
+        counts.add(&rows.row(index)?);
+    }
+
+    let names: Vec<String> = rows.names().iter().map(|name| String::from_utf8_lossy(name).into_owned()).collect();
+    let width = names.iter().map(|name| name.len()).chain(["#=GC seq_cons".len()]).max().unwrap_or(0);
+
+    writeln!(writer, "# STOCKHOLM 1.0")?;
+    for (index, name) in names.iter().enumerate() {
+        writeln!(writer, "{:width$} {}", name, String::from_utf8_lossy(&rows.row(index)?), width = width)?;
+    }
+    if let Some(reference) = reference {
+        writeln!(writer, "{:width$} {}", "#=GC RF", String::from_utf8_lossy(reference), width = width)?;
+    }
+    writeln!(writer, "{:width$} {}", "#=GC seq_cons", String::from_utf8_lossy(&counts.consensus()), width = width)?;
+    writeln!(writer, "//")?;
+    writer.flush().context("Failed to write Stockholm alignment")?;
+    Ok(())
+}
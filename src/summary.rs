@@ -0,0 +1,52 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+use crate::logging;
+
+/// Counts of reads dropped or altered during a run, reported once the run finishes
+#[derive(Default)]
+pub struct Summary {
+    pub too_short: AtomicUsize,
+    pub too_long: AtomicUsize,
+    pub low_coverage: AtomicUsize,
+    pub unanchored: AtomicUsize,
+    pub primer_trimmed: AtomicUsize,
+    pub mapped: AtomicUsize,
+    pub unmapped: AtomicUsize,
+}
+
+impl Summary {
+    pub fn increment(counter: &AtomicUsize) {
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn mapped(&self) -> usize {
+        self.mapped.load(Ordering::Relaxed)
+    }
+
+    /// Report the non-zero counts
+    pub fn report(&self) {
+        let too_short = self.too_short.load(Ordering::Relaxed);
+        if too_short > 0 {
+            logging::report!("Filtered {} reads shorter than --min-read-len", too_short);
+        }
+        let too_long = self.too_long.load(Ordering::Relaxed);
+        if too_long > 0 {
+            logging::report!("Filtered {} reads longer than --max-read-len", too_long);
+        }
+        let low_coverage = self.low_coverage.load(Ordering::Relaxed);
+        if low_coverage > 0 {
+            logging::report!("Filtered {} reads covering less of the reference than --min-ref-coverage", low_coverage);
+        }
+        let unanchored = self.unanchored.load(Ordering::Relaxed);
+        if unanchored > 0 {
+            logging::report!("{} reads mapped without consuming any reference bases (no reference anchor)", unanchored);
+        }
+        let primer_trimmed = self.primer_trimmed.load(Ordering::Relaxed);
+        if primer_trimmed > 0 {
+            logging::report!("Trimmed primers from {} reads", primer_trimmed);
+        }
+        let unmapped = self.unmapped.load(Ordering::Relaxed);
+        if unmapped > 0 {
+            logging::report!("{} reads did not map to the reference", unmapped);
+        }
+    }
+}
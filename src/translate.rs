@@ -0,0 +1,63 @@
+/// Standard genetic code, indexed by codon with bases ordered TCAG
+const CODON_TABLE: &[u8; 64] = b"FFLLSSSSYY**CC*WLLLLPPPPHHQQRRRRIIIMTTTTNNKKSSRRVVVVAAAADDEEGGGG";
+
+fn base_index(base: u8) -> Option<usize> {
+    match base.to_ascii_uppercase() {
+        b'T' | b'U' => Some(0),
+        b'C' => Some(1),
+        b'A' => Some(2),
+        b'G' => Some(3),
+        _ => None,
+    }
+}
+
+/// Translate a single codon, anything containing an ambiguous base becomes X
+pub fn translate_codon(codon: &[u8]) -> u8 {
+    let mut index = 0;
+    for &base in codon {
+        match base_index(base) {
+            Some(i) => index = index * 4 + i,
+            None => return b'X',
+        }
+    }
+    CODON_TABLE[index]
+}
+
+/// Translate a gapped nucleotide row into a gapped amino acid row
+/// Codons are read in reference columns starting at `frame` so gaps never shift the frame.
+/// Codons that are all gaps become `-`, codons that are partly gaps (at alignment ends or
+/// across a frameshift) become `X`, and any trailing partial codon is dropped
+pub fn translate_row(row: &[u8], frame: usize) -> Vec<u8> {
+    row.get(frame..)
+        .unwrap_or_default()
+        .chunks_exact(3)
+        .map(|codon| {
+            let gaps = codon.iter().filter(|&&b| b == b'-').count();
+            match gaps {
+                0 => translate_codon(codon),
+                3 => b'-',
+                _ => b'X',
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn translates_known_row() {
+        assert_eq!(translate_row(b"ATGGCC---TAA", 0), b"MA-*");
+    }
+
+    #[test]
+    fn translates_in_frame() {
+        assert_eq!(translate_row(b"AATGTTTG", 1), b"MF");
+    }
+
+    #[test]
+    fn partial_gap_codons_become_x() {
+        assert_eq!(translate_row(b"AT-NNN", 0), b"XX");
+    }
+}
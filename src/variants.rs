@@ -0,0 +1,46 @@
+use crate::{sidecar, CigarOperation};
+
+/// Compact variant notation for a read, e.g. `100A>G,150delT,200insAC`, from its CIGAR and the bases
+/// Insertions are placed at the reference base they come before, as in the insertions sidecar
+/// `contig_offset` is added to every reported position, placing --region coordinates back on the original contig
+pub fn variant_string(cigar: &[CigarOperation], query: &[u8], reference: &[u8], ref_start: usize, coordinate_base: u8, contig_offset: usize) -> String {
+    let position = |ref_pos: usize| sidecar::position(ref_pos + contig_offset, coordinate_base);
+    let mut variants = Vec::new();
+    let mut seq_pos = 0;
+    let mut ref_pos = ref_start;
+    for op in cigar {
+        match *op {
+            CigarOperation::Match(count) | CigarOperation::Equal(count) | CigarOperation::Diff(count) => {
+                let pairs = query.iter().skip(seq_pos).zip(reference.iter().skip(ref_pos)).take(count);
+                for (offset, (read_base, ref_base)) in pairs.enumerate() {
+                    if !read_base.eq_ignore_ascii_case(ref_base) {
+                        variants.push(format!(
+                            "{}{}>{}",
+                            position(ref_pos + offset), *ref_base as char, *read_base as char
+                        ));
+                    }
+                }
+                seq_pos += count;
+                ref_pos += count;
+            }
+            CigarOperation::Insertion(count) => {
+                let bases = query.get(seq_pos..seq_pos + count).unwrap_or_default();
+                variants.push(format!("{}ins{}", position(ref_pos), String::from_utf8_lossy(bases)));
+                seq_pos += count;
+            }
+            CigarOperation::Deletion(count) => {
+                let bases = reference.get(ref_pos..ref_pos + count).unwrap_or_default();
+                variants.push(format!("{}del{}", position(ref_pos), String::from_utf8_lossy(bases)));
+                ref_pos += count;
+            }
+            CigarOperation::Skipped(count) => ref_pos += count,
+            CigarOperation::SoftClip(count) => seq_pos += count,
+            CigarOperation::HardClip(_) | CigarOperation::Pad(_) => {}
+        }
+    }
+    if variants.is_empty() {
+        "none".to_string()
+    } else {
+        variants.join(",")
+    }
+}
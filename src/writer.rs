@@ -0,0 +1,92 @@
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufWriter, Read, Seek, SeekFrom, Write};
+use std::num::NonZeroUsize;
+use anyhow::{Result, Context};
+use noodles::bgzf;
+
+/// An output file, kept as its concrete type so BGZF output can be finished and any error reported
+pub enum OutputWriter {
+    Plain(BufWriter<File>),
+    Bgzf(bgzf::Writer<File>),
+    MultithreadedBgzf(bgzf::MultithreadedWriter),
+    // Discards everything, for --benchmark-mode
+    Sink(io::Sink),
+}
+
+impl OutputWriter {
+    /// Flush everything written, and for BGZF the final block and EOF marker, which dropping the writer would do silently
+    pub fn finish(&mut self) -> Result<()> {
+        match std::mem::replace(self, OutputWriter::Sink(io::sink())) {
+            OutputWriter::Plain(mut writer) => writer.flush().context("Failed to write output file"),
+            OutputWriter::Bgzf(writer) => writer.finish().map(drop).context("Failed to finish BGZF output file"),
+            OutputWriter::MultithreadedBgzf(mut writer) => writer.finish().map(drop).context("Failed to finish BGZF output file"),
+            OutputWriter::Sink(_) => Ok(()),
+        }
+    }
+}
+
+impl Write for OutputWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            OutputWriter::Plain(writer) => writer.write(buf),
+            OutputWriter::Bgzf(writer) => writer.write(buf),
+            OutputWriter::MultithreadedBgzf(writer) => writer.write(buf),
+            OutputWriter::Sink(writer) => writer.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            OutputWriter::Plain(writer) => writer.flush(),
+            OutputWriter::Bgzf(writer) => writer.flush(),
+            OutputWriter::MultithreadedBgzf(writer) => writer.flush(),
+            OutputWriter::Sink(writer) => writer.flush(),
+        }
+    }
+}
+
+/// Open an output file for writing, BGZF compressed when requested or when the path ends in `.bgz`
+/// With `append` new records are added to the end of any existing file instead of replacing it
+/// More than one `compression_threads` compresses BGZF blocks in parallel
+pub fn open_output(path: &str, bgzf: bool, append: bool, compression_threads: usize) -> Result<OutputWriter> {
+    let bgzf = bgzf || path.ends_with(".bgz");
+    let file = if append {
+        let mut file = OpenOptions::new().read(true).append(true).create(true).open(path)
+            .context("Failed to open output file for appending")?;
+        // Make sure the first appended header starts on its own line
+        if !bgzf && !ends_with_newline(&mut file)? {
+            file.write_all(b"\n").context("Failed to write to output file")?;
+        }
+        file
+    } else {
+        File::create(path).context("Failed to create output file")?
+    };
+
+    match NonZeroUsize::new(compression_threads) {
+        Some(workers) if bgzf && workers.get() > 1 => Ok(OutputWriter::MultithreadedBgzf(bgzf::MultithreadedWriter::with_worker_count(workers, file))),
+        _ if bgzf => Ok(OutputWriter::Bgzf(bgzf::Writer::new(file))),
+        _ => Ok(OutputWriter::Plain(BufWriter::new(file))),
+    }
+}
+
+/// Whether a file is empty or its last byte is a newline
+fn ends_with_newline(file: &mut File) -> Result<bool> {
+    if file.metadata().context("Failed to read output file metadata")?.len() == 0 {
+        return Ok(true);
+    }
+    let mut last = [0u8; 1];
+    file.seek(SeekFrom::End(-1)).context("Failed to read output file")?;
+    file.read_exact(&mut last).context("Failed to read output file")?;
+    Ok(last[0] == b'\n')
+}
+
+/// Path of a numbered output chunk, e.g. `aln.fa` becomes `aln.part001.fa`
+pub fn chunk_path(path: &str, chunk: usize) -> String {
+    let path = std::path::Path::new(path);
+    let file_name = path.file_name().map(|name| name.to_string_lossy().into_owned()).unwrap_or_default();
+    let chunk_name = match file_name.split_once('.') {
+        Some((stem, extension)) => format!("{}.part{:03}.{}", stem, chunk, extension),
+        None => format!("{}.part{:03}", file_name, chunk),
+    };
+    path.with_file_name(chunk_name).to_string_lossy().into_owned()
+}
@@ -0,0 +1,74 @@
+use std::fs::File;
+use std::io::{BufWriter, Result, Write};
+use std::path::{Path, PathBuf};
+
+/// Bases per FASTA line in the generated files
+const LINE_WIDTH: usize = 60;
+
+/// A generated reference and reads sampled from it, written under a directory
+pub struct SyntheticData {
+    pub reference: PathBuf,
+    pub reads: PathBuf,
+    pub read_count: usize,
+}
+
+/// Small xorshift generator so the data is the same on every run without pulling in a rand crate
+struct Rng(u64);
+
+impl Rng {
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+
+    fn below(&mut self, n: usize) -> usize {
+        (self.next() % n as u64) as usize
+    }
+
+    fn base(&mut self) -> u8 {
+        b"ACGT"[self.below(4)]
+    }
+}
+
+/// Write a random reference of `reference_len` bases and `read_count` reads of `read_len` bases sampled from it
+/// Each read carries about 1% substitutions and the odd single base deletion, like a low diversity outbreak sample
+pub fn write_dataset(dir: &Path, reference_len: usize, read_count: usize, read_len: usize, seed: u64) -> Result<SyntheticData> {
+    assert!(read_len <= reference_len, "reads can't be longer than the reference");
+    // xorshift never leaves zero
+    let mut rng = Rng(seed.max(1));
+    let reference: Vec<u8> = (0..reference_len).map(|_| rng.base()).collect();
+
+    let reference_path = dir.join("reference.fa");
+    let mut writer = BufWriter::new(File::create(&reference_path)?);
+    write_record(&mut writer, "reference", &reference)?;
+    writer.flush()?;
+
+    let reads_path = dir.join("reads.fa");
+    let mut writer = BufWriter::new(File::create(&reads_path)?);
+    for i in 0..read_count {
+        let start = rng.below(reference_len - read_len + 1);
+        let mut read = Vec::with_capacity(read_len);
+        for &base in &reference[start..start + read_len] {
+            match rng.below(1000) {
+                0..=9 => read.push(rng.base()),
+                10 => {}
+                _ => read.push(base),
+            }
+        }
+        write_record(&mut writer, &format!("read{}", i + 1), &read)?;
+    }
+    writer.flush()?;
+
+    Ok(SyntheticData { reference: reference_path, reads: reads_path, read_count })
+}
+
+fn write_record(writer: &mut impl Write, name: &str, sequence: &[u8]) -> Result<()> {
+    writeln!(writer, ">{}", name)?;
+    for line in sequence.chunks(LINE_WIDTH) {
+        writer.write_all(line)?;
+        writeln!(writer)?;
+    }
+    Ok(())
+}